@@ -9,6 +9,10 @@ use embedded_nal::{TcpClientStack, UdpClientStack};
 use no_std_net::ToSocketAddrs;
 use vap_common::capability::CapabilityCode;
 
+/// OSCORE (RFC 8613), for clients that can't afford DTLS handshakes. It works
+/// on messages in their wire format (see `Packet::to_bytes` and `Packet::from_bytes`).
+pub use vap_common::oscore;
+
 pub struct VAPClient<Endpoint> {
     endpoint: Endpoint,
     name: String,
@@ -0,0 +1,50 @@
+//! The encoding of every VAP message: MsgPack, with structures written as maps
+//! (field names as keys). Structures written as arrays (positional) are still
+//! accepted when decoding, as older implementations sent them that way.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use rmp_serde::{decode::Error as DecodeError, encode::Error as EncodeError};
+
+/// Encodes a message as it should be sent
+pub fn to_vec<T: Serialize + ?Sized>(msg: &T) -> Result<Vec<u8>, EncodeError> {
+    rmp_serde::to_vec_named(msg)
+}
+
+/// Decodes a message, whether it's structures are maps or arrays
+pub fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T, DecodeError> {
+    rmp_serde::from_slice(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{msg_notification::Data, MsgConnect, MsgNotification};
+
+    #[test]
+    fn accepts_positional_structures() {
+        let msg = MsgConnect {
+            id: "com.example.test".into(),
+            name: "Test".into(),
+            vap_version: "0.2.0".into(),
+            min_vap_version: Some("0.1.0".into()),
+            unique_authentication_token: None,
+            intents_digest: None,
+        };
+
+        let positional = rmp_serde::to_vec(&msg).unwrap();
+        let decoded: MsgConnect = from_slice(&positional).unwrap();
+        assert_eq!(to_vec(&decoded).unwrap(), to_vec(&msg).unwrap());
+
+        let msg = MsgNotification {
+            skill_id: "com.example.test".into(),
+            data: vec![Data::CanYouAnswer {
+                request_id: 3,
+                confidence: 0.5,
+            }],
+        };
+        let positional = rmp_serde::to_vec(&msg).unwrap();
+        let decoded: MsgNotification = from_slice(&positional).unwrap();
+        assert_eq!(to_vec(&decoded).unwrap(), to_vec(&msg).unwrap());
+    }
+}
@@ -1,4 +1,8 @@
+pub mod encoding;
+pub mod oscore;
 pub mod structures;
+pub mod validation;
+pub mod version;
 
 #[cfg(test)]
 mod tests {
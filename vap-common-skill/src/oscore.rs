@@ -0,0 +1,65 @@
+//! The OSCORE security contexts of skills and registries (see SKILLS.MD).
+//! Both are derived from the `uniqueAuthenticationToken` (Master Secret) and
+//! the `oscoreIdContext` (ID Context) of the connect answer.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub use vap_common::oscore::*;
+
+/// The Sender ID of skills, the registry's is empty
+pub const SKILL_ID: &[u8] = &[0x01];
+/// The Sender ID of the registry
+pub const REGISTRY_ID: &[u8] = &[];
+
+/// The security context a skill uses to talk with the registry
+pub fn skill_context(token: &str, id_context: &str) -> SecurityContext {
+    SecurityContext::new(token.as_bytes(), &[], Some(id_context.as_bytes()), SKILL_ID, REGISTRY_ID)
+}
+
+/// The security context the registry uses to talk with a skill
+pub fn registry_context(token: &str, id_context: &str) -> SecurityContext {
+    SecurityContext::new(token.as_bytes(), &[], Some(id_context.as_bytes()), REGISTRY_ID, SKILL_ID)
+}
+
+/// What skills put in Auth-Token when observing under OSCORE: observation
+/// can't be protected, and the token itself is the Master Secret.
+pub fn observation_token(token: &str, id_context: &str) -> String {
+    let hkdf = Hkdf::<Sha256>::new(Some(id_context.as_bytes()), token.as_bytes());
+    let mut out = [0u8; 16];
+    hkdf.expand(b"VAP observe", &mut out).expect("Small enough for HKDF");
+    out.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skills_and_registries_understand_each_other() {
+        let mut skill = skill_context("token", "context");
+        let mut registry = registry_context("token", "context");
+
+        // GET vap/skillRegistry
+        let request = [0x41, 0x01, 0x12, 0x34, 0xAA, 0xB3, b'v', b'a', b'p', 0x0D, 0x00, b's', b'k', b'i', b'l', b'l', b'R', b'e', b'g', b'i', b's', b't', b'r', b'y'];
+        let (protected, request_ref) = skill.protect_request(&request).unwrap();
+        assert_eq!(request_id_context(&protected).unwrap().as_deref(), Some(&b"context"[..]));
+        let (unprotected, registry_ref) = registry.unprotect_request(&protected).unwrap();
+        assert_eq!(&unprotected[..], &request[..]);
+
+        // 2.05 Content, "hi"
+        let response = [0x61, 0x45, 0x12, 0x34, 0xAA, 0xFF, b'h', b'i'];
+        let protected = registry.protect_response(&response, &registry_ref).unwrap();
+        assert_eq!(&skill.unprotect_response(&protected, &request_ref).unwrap()[..], &response[..]);
+
+        let mut other = registry_context("other", "context");
+        assert_eq!(other.unprotect_request(&skill.protect_request(&request).unwrap().0), Err(Error::Decryption));
+    }
+
+    #[test]
+    fn observation_tokens_depend_on_the_context() {
+        assert_eq!(observation_token("token", "a"), observation_token("token", "a"));
+        assert_ne!(observation_token("token", "a"), observation_token("token", "b"));
+        assert_ne!(observation_token("token", "a"), "token");
+    }
+}
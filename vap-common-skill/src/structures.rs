@@ -1,11 +1,22 @@
 use std::{
     collections::HashMap,
+    convert::TryFrom,
     fmt::{Display, Write},
     hash::Hash,
+    str::FromStr,
 };
 
 use serde::{Deserialize, Serialize};
-use unic_langid::LanguageIdentifier;
+use unic_langid::{subtags, LanguageIdentifier, LanguageIdentifierError};
+
+use crate::version::{Version, VersionParseError, VersionRange};
+
+/// The id used to refer to the assistant core itself
+pub const SYSTEM_SELF_ID: &str = "vap.SYSTEM";
+
+/// The CoAP option (Auth-Token) where skills put their `uniqueAuthenticationToken`
+/// in every message after connect. It's from the experimental range and elective.
+pub const AUTH_TOKEN_OPTION: u16 = 65000;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MsgConnect {
@@ -15,14 +26,67 @@ pub struct MsgConnect {
     /// A human readable name for the skill
     pub name: String,
 
+    /// The newest VAP version supported by the skill
     #[serde(rename = "vapVersion")]
-    pub vap_version: String
+    pub vap_version: String,
+
+    /// The oldest VAP version supported by the skill, if not present only
+    /// `vap_version` is supported
+    #[serde(rename = "minVapVersion", default)]
+    pub min_vap_version: Option<String>,
+
+    /// The token given by the registry on a previous connection, proves that
+    /// this is the same skill when reconnecting
+    #[serde(rename = "uniqueAuthenticationToken", default)]
+    pub unique_authentication_token: Option<String>,
+
+    /// Identifies the intents the skill would register (e.g: a hash of them),
+    /// the registry only regards its intents as known if they were registered
+    /// on a connection with this same digest
+    #[serde(rename = "intentsDigest", default)]
+    pub intents_digest: Option<String>,
+}
+
+impl MsgConnect {
+    /// The versions supported by the skill
+    pub fn version_range(&self) -> Result<VersionRange, VersionParseError> {
+        let max = self.vap_version.parse()?;
+        let min = match &self.min_vap_version {
+            Some(v) => v.parse()?,
+            None => max,
+        };
+
+        Ok(VersionRange::new(min, max))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MsgConnectResponse {
     /// A list of languages currently in use by the voice assistant
-    pub langs: Vec<Language>
+    pub langs: Vec<Language>,
+
+    /// The version both the skill and the registry agreed on, filled by the registry
+    #[serde(rename = "vapVersion", default)]
+    pub vap_version: Option<Version>,
+
+    /// A token that identifies this skill from now on, only present if the
+    /// skill didn't send a valid one. Filled by the registry
+    #[serde(rename = "uniqueAuthenticationToken", default)]
+    pub unique_authentication_token: Option<String>,
+
+    /// The ID Context of the OSCORE security context of this connection, new
+    /// on every connect. Filled by the registry
+    #[serde(rename = "oscoreIdContext", default)]
+    pub oscore_id_context: Option<String>,
+    /// Whether the registry still has the intents the skill sent on a previous
+    /// connection, if so the skill doesn't need to send them again. Filled by the registry
+    #[serde(rename = "intentsKnown", default)]
+    pub intents_known: bool,
+
+    /// How often (in seconds) the skill has to send a heartbeat, if not present
+    /// there's no need to. Filled by the registry
+    #[serde(rename = "heartbeatSecs", default)]
+    pub heartbeat_secs: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -60,12 +124,29 @@ pub mod msg_register_intents {
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct NluDataSlot {
         pub name: String,
+
+        /// Name of the entity this slot takes values from
         pub entity: String,
+
+        /// If true the assistant must ask for it before sending the request
+        #[serde(default)]
+        pub required: bool,
+
+        /// If required and not present the slot will be asked with this
+        #[serde(default)]
+        pub prompt: Option<String>,
+
+        /// If the prompt failed, the slot will be asked again with this
+        #[serde(default)]
+        pub reprompt: Option<String>,
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct NluDataEntity {
         pub name: String,
+
+        /// If true only the values in `data` (and their synonyms) will be accepted
+        #[serde(default)]
         pub strict: bool,
         pub data: Vec<NluDataEntityData>,
     }
@@ -77,17 +158,61 @@ pub mod msg_register_intents {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// A BCP-47 language identifier (e.g: "en-US", "sr-Latn-RS", "de-DE-1996").
+/// 
+/// Can be received either as a structure or in it's compact form (a string),
+/// it is always sent as a structure, unless `compact_language` is used.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "LanguageRepr")]
 pub struct Language {
-    // Better this or a single string?
     /// The country code of the language
     pub country: Option<String>,
 
     /// The language code
     pub language: String,
 
-    /// The extra code for the language
-    pub extra: Option<String>, // is this necessary?
+    /// The script code of the language
+    pub script: Option<String>,
+
+    /// Any variants of the language
+    pub variants: Vec<String>,
+}
+
+/// All the forms a language can take on the wire
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LanguageRepr {
+    Compact(String),
+    Full(LanguageFields),
+}
+
+#[derive(Deserialize)]
+struct LanguageFields {
+    country: Option<String>,
+    language: String,
+
+    // Older implementations called this "extra"
+    #[serde(alias = "extra")]
+    script: Option<String>,
+
+    #[serde(default)]
+    variants: Vec<String>,
+}
+
+impl TryFrom<LanguageRepr> for Language {
+    type Error = LanguageIdentifierError;
+
+    fn try_from(repr: LanguageRepr) -> Result<Self, Self::Error> {
+        match repr {
+            LanguageRepr::Compact(s) => s.parse(),
+            LanguageRepr::Full(f) => Ok(Language {
+                country: f.country,
+                language: f.language,
+                script: f.script,
+                variants: f.variants,
+            }),
+        }
+    }
 }
 
 impl From<LanguageIdentifier> for Language {
@@ -95,19 +220,74 @@ impl From<LanguageIdentifier> for Language {
         Language {
             country: l.region.map(|r| r.to_string()),
             language: l.language.to_string(),
-            extra: l.script.map(|s| s.to_string()),
+            script: l.script.map(|s| s.to_string()),
+            variants: l.variants().map(|v| v.to_string()).collect(),
         }
     }
 }
 
-impl From<Language> for LanguageIdentifier {
-    fn from(lang: Language) -> Self {
-        LanguageIdentifier::from_parts(
-            lang.language.parse().unwrap(),
-            lang.extra.and_then(|e| e.parse().ok()),
-            lang.country.and_then(|c| c.parse().ok()),
-            &[],
-        )
+impl TryFrom<&Language> for LanguageIdentifier {
+    type Error = LanguageIdentifierError;
+
+    fn try_from(lang: &Language) -> Result<Self, Self::Error> {
+        let variants = lang
+            .variants
+            .iter()
+            .map(|v| v.parse())
+            .collect::<Result<Vec<subtags::Variant>, _>>()?;
+
+        Ok(LanguageIdentifier::from_parts(
+            lang.language.parse()?,
+            lang.script.as_deref().map(str::parse).transpose()?,
+            lang.country.as_deref().map(str::parse).transpose()?,
+            &variants,
+        ))
+    }
+}
+
+impl TryFrom<Language> for LanguageIdentifier {
+    type Error = LanguageIdentifierError;
+
+    fn try_from(lang: Language) -> Result<Self, Self::Error> {
+        LanguageIdentifier::try_from(&lang)
+    }
+}
+
+impl FromStr for Language {
+    type Err = LanguageIdentifierError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse::<LanguageIdentifier>()?.into())
+    }
+}
+
+/// Writes the compact form of the language (e.g: "sr-Latn-RS")
+impl Display for Language {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str(&self.language)?;
+        for part in self.script.iter().chain(&self.country).chain(&self.variants) {
+            fmt.write_char('-')?;
+            fmt.write_str(part)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub mod compact_language {
+    //! Use with `#[serde(with = "compact_language")]` to send a language as a
+    //! string instead of a structure.
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Language;
+
+    pub fn serialize<S: Serializer>(lang: &Language, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(lang)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Language, D::Error> {
+        Language::deserialize(deserializer)
     }
 }
 
@@ -116,6 +296,7 @@ pub struct MsgRegisterIntentsResponse {}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MsgSkillRequest {
+    #[serde(rename = "requestId", alias = "request_id")]
     pub request_id: u64,
     pub client: msg_skill_request::ClientData,
     pub request: msg_skill_request::RequestData,
@@ -124,6 +305,8 @@ pub struct MsgSkillRequest {
 pub mod msg_skill_request {
     use serde::{Deserialize, Serialize};
 
+    use super::{AssociativeMap, Value, SYSTEM_SELF_ID};
+
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct ClientData {
         #[serde(rename = "systemId")]
@@ -145,23 +328,122 @@ pub mod msg_skill_request {
         #[serde(rename = "event")]
         Event,
 
-        #[serde(rename = "canAnswer")]
+        // Older implementations called this "canAnswer"
+        #[serde(rename = "canYouAnswer", alias = "canAnswer")]
         CanAnswer,
+
+        /// Started by the assistant itself, not as a response to the user
+        #[serde(rename = "standalone")]
+        Standalone,
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct RequestData {
         #[serde(rename = "type")]
         pub type_: RequestDataKind,
+
+        /// Name of the intent, empty for events
+        #[serde(default)]
         pub intent: String,
         pub locale: String,
+
+        /// What the user actually said, if the request comes from an utterance
+        #[serde(default)]
+        pub input: Option<String>,
         pub slots: Vec<RequestSlot>,
+
+        /// Data that came with the request (e.g: the audio or an image)
+        #[serde(default)]
+        pub capabilities: Vec<super::PlainCapability>,
+
+        /// What happened, only for events
+        #[serde(default)]
+        pub event: Option<EventData>,
+    }
+
+    impl RequestData {
+        /// Makes the data for an event request
+        pub fn event<S: Into<String>>(event: EventData, locale: S) -> Self {
+            RequestData {
+                type_: RequestDataKind::Event,
+                intent: String::new(),
+                locale: locale.into(),
+                input: None,
+                slots: vec![],
+                capabilities: vec![],
+                event: Some(event),
+            }
+        }
+    }
+
+    /// Something that happened in the system which is not an utterance
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    pub struct EventData {
+        /// A namespaced name like "org.company.event", see [`system_events`]
+        /// for the ones sent by the system itself.
+        pub name: String,
+
+        /// Who produced the event: a client, a skill or the system itself
+        pub source: String,
+
+        /// Any data that came with the event, depends on the event itself
+        #[serde(default)]
+        pub payload: AssociativeMap,
+    }
+
+    impl EventData {
+        pub fn new<S1: Into<String>, S2: Into<String>>(name: S1, source: S2) -> Self {
+            EventData {
+                name: name.into(),
+                source: source.into(),
+                payload: AssociativeMap::new(),
+            }
+        }
+
+        pub fn with_data<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+            self.payload.insert(key.into(), value.into());
+            self
+        }
+
+        /// A client connected to the assistant
+        pub fn client_connected<S: Into<String>>(client_id: S) -> Self {
+            Self::new(system_events::CLIENT_CONNECTED, SYSTEM_SELF_ID)
+                .with_data("clientId", client_id.into())
+        }
+
+        /// A timer set previously has fired
+        pub fn timer_fired<S: Into<String>>(timer_id: S) -> Self {
+            Self::new(system_events::TIMER_FIRED, SYSTEM_SELF_ID)
+                .with_data("timerId", timer_id.into())
+        }
+
+        /// A client has finished it's session with the user
+        pub fn session_ended<S: Into<String>>(client_id: S) -> Self {
+            Self::new(system_events::SESSION_ENDED, SYSTEM_SELF_ID)
+                .with_data("clientId", client_id.into())
+        }
+    }
+
+    pub mod system_events {
+        //! Events sent by the system itself, all of them have "vap.SYSTEM" as source.
+
+        /// Payload: "clientId" -> String
+        pub const CLIENT_CONNECTED: &str = "vap.SYSTEM.clientConnected";
+        /// Payload: "timerId" -> String
+        pub const TIMER_FIRED: &str = "vap.SYSTEM.timerFired";
+        /// Payload: "clientId" -> String
+        pub const SESSION_ENDED: &str = "vap.SYSTEM.sessionEnded";
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct RequestSlot {
         pub name: String,
+        /// What the user said
         pub value: Option<String>,
+        /// The value of the entity that `value` is (itself or one of its
+        /// synonyms), filled by the registry
+        #[serde(default)]
+        pub resolved: Option<String>,
     }
 }
 
@@ -290,6 +572,84 @@ pub struct MsgSkillClose {
     pub skill_id: String,
 }
 
+/// Sent periodically by skills to let the registry know they are still alive
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgHeartbeat {
+    #[serde(rename = "skillId")]
+    pub skill_id: String,
+}
+
+/// The body of any error answer, shared by registries, skills and clients.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VapError {
+    /// Same CoAP code as the answer (e.g: 404 for not found)
+    pub code: u16,
+
+    /// An identifier of what happened, see [`vap_error`] for the known ones
+    #[serde(rename = "type")]
+    pub type_: String,
+
+    /// What caused the error, not every error has one
+    pub object: Option<String>,
+
+    /// Some online documentation about the error
+    #[serde(rename = "docRef")]
+    pub doc_ref: Option<String>,
+}
+
+impl VapError {
+    pub fn new<S: Into<String>>(code: u16, type_: S) -> Self {
+        VapError {
+            code,
+            type_: type_.into(),
+            object: None,
+            doc_ref: None,
+        }
+    }
+
+    pub fn with_object<S: Into<String>>(mut self, object: S) -> Self {
+        self.object = Some(object.into());
+        self
+    }
+
+    pub fn with_doc_ref<S: Into<String>>(mut self, doc_ref: S) -> Self {
+        self.doc_ref = Some(doc_ref.into());
+        self
+    }
+}
+
+impl Display for VapError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{} {}", self.code, self.type_)?;
+        if let Some(object) = &self.object {
+            write!(fmt, " ({})", object)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub mod vap_error {
+    //! The error types defined by the protocol, used in `VapError::type_`
+
+    pub const NOT_FOUND: &str = "not found";
+    pub const MALFORMED_CONTENT: &str = "malformed content";
+    pub const MISSING_FIELD: &str = "missing field";
+    pub const METHOD_NOT_ALLOWED: &str = "method not allowed";
+    pub const INTERNAL_ERROR: &str = "internal error";
+    pub const CONNECTION_DENIED: &str = "connectionDenied";
+    pub const APPROVAL_PENDING: &str = "approval pending";
+    pub const TOO_MANY_REQUESTS: &str = "too many requests";
+    pub const VERSION_INCOMPATIBLE: &str = "vapVersion incompatible";
+    pub const UNAUTHORIZED: &str = "unauthorized";
+    pub const WRONG_SKILL_ID: &str = "wrong skillId";
+    pub const WRONG_REQUEST_ID: &str = "wrong requestId";
+    pub const MISSING_ENTITY: &str = "missing entity";
+    pub const MISSING_SLOT: &str = "missing slot";
+    pub const DUPLICATED_NAME: &str = "duplicated name";
+    pub const INVALID_LANGUAGE: &str = "invalid language";
+}
+
 /// A structure describing Capability data
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PlainCapability {
@@ -428,3 +788,60 @@ impl From<&str> for Value {
         Value::String(s.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+
+    use unic_langid::LanguageIdentifier;
+
+    use super::{compact_language, Language};
+
+    #[test]
+    fn language_round_trip() {
+        let id: LanguageIdentifier = "sr-Latn-RS-1996".parse().unwrap();
+        let lang = Language::from(id.clone());
+        assert_eq!(lang.script.as_deref(), Some("Latn"));
+        assert_eq!(lang.variants, vec!["1996".to_string()]);
+        assert_eq!(lang.to_string(), "sr-Latn-RS-1996");
+        assert_eq!(LanguageIdentifier::try_from(&lang).unwrap(), id);
+    }
+
+    #[test]
+    fn invalid_language_is_an_error() {
+        let lang = Language {
+            country: Some("US".into()),
+            language: "not a language".into(),
+            script: None,
+            variants: vec![],
+        };
+        assert!(LanguageIdentifier::try_from(lang).is_err());
+    }
+
+    #[test]
+    fn language_wire_forms() {
+        let expected: Language = "en-US".parse().unwrap();
+
+        // Older form, with "extra" instead of "script" and no variants
+        let mut old = HashMap::new();
+        old.insert("country", Some("US"));
+        old.insert("language", Some("en"));
+        old.insert("extra", None);
+        let data = rmp_serde::to_vec(&old).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Language>(&data).unwrap(), expected);
+
+        let data = rmp_serde::to_vec(&(Some("US"), "en", None::<String>)).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Language>(&data).unwrap(), expected);
+
+        let data = rmp_serde::to_vec("en-US").unwrap();
+        assert_eq!(rmp_serde::from_slice::<Language>(&data).unwrap(), expected);
+
+        let mut data = vec![];
+        compact_language::serialize(&expected, &mut rmp_serde::Serializer::new(&mut data)).unwrap();
+        assert_eq!(data, rmp_serde::to_vec("en-US").unwrap());
+
+        let data = rmp_serde::to_vec("not a language").unwrap();
+        assert!(rmp_serde::from_slice::<Language>(&data).is_err());
+    }
+}
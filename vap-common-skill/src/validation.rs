@@ -0,0 +1,181 @@
+//! Checks on the data sent by skills that can't be expressed by the structures
+//! themselves.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+use unic_langid::LanguageIdentifier;
+
+use crate::structures::{
+    msg_register_intents::{NluData, NluDataIntent},
+    vap_error, Language, MsgRegisterIntents, VapError,
+};
+
+/// Code of errors for data that is present but not valid (Bad Request)
+const BAD_REQUEST: u16 = 400;
+/// Code of errors for data that references something not present (Request Entity Incomplete)
+const ENTITY_INCOMPLETE: u16 = 408;
+
+/// Makes sure that the NLU data of a skill is coherent:
+/// * Languages are valid and there's only one set per language.
+/// * Intents, slots and entities names are unique (per language).
+/// * Slots reference declared entities.
+/// * Utterance placeholders (`{slot}`) reference declared slots.
+///
+/// Returns the first problem found as the error to be sent back to the skill.
+pub fn validate_register_intents(msg: &MsgRegisterIntents) -> Result<(), VapError> {
+    let mut langs = HashSet::new();
+    for data in &msg.nlu_data {
+        let lang = language_str(&data.language)?;
+        if !langs.insert(lang.clone()) {
+            return Err(duplicated(lang));
+        }
+
+        validate_nlu_data(data)?;
+    }
+
+    Ok(())
+}
+
+fn validate_nlu_data(data: &NluData) -> Result<(), VapError> {
+    let mut entities = HashSet::new();
+    for entity in &data.entities {
+        if !entities.insert(entity.name.as_str()) {
+            return Err(duplicated(&entity.name));
+        }
+    }
+
+    let mut intents = HashSet::new();
+    for intent in &data.intents {
+        if !intents.insert(intent.name.as_str()) {
+            return Err(duplicated(&intent.name));
+        }
+
+        validate_intent(intent, &entities)?;
+    }
+
+    Ok(())
+}
+
+fn validate_intent(intent: &NluDataIntent, entities: &HashSet<&str>) -> Result<(), VapError> {
+    let mut slots = HashSet::new();
+    for slot in &intent.slots {
+        if !slots.insert(slot.name.as_str()) {
+            return Err(duplicated(format!("{}.{}", intent.name, slot.name)));
+        }
+
+        if !entities.contains(slot.entity.as_str()) {
+            return Err(VapError::new(ENTITY_INCOMPLETE, vap_error::MISSING_ENTITY)
+                .with_object(&slot.entity));
+        }
+    }
+
+    for utterance in &intent.utterances {
+        for placeholder in placeholders(&utterance.text) {
+            if !slots.contains(placeholder) {
+                return Err(VapError::new(ENTITY_INCOMPLETE, vap_error::MISSING_SLOT)
+                    .with_object(placeholder));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the names of the slots referenced by an utterance, in the form of
+/// `turn on the {device}`.
+pub fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    text.split('{')
+        .skip(1)
+        .filter_map(|s| s.split_once('}').map(|(name, _)| name.trim()))
+}
+
+fn language_str(lang: &Language) -> Result<String, VapError> {
+    match LanguageIdentifier::try_from(lang) {
+        Ok(id) => Ok(id.to_string()),
+        Err(_) => Err(VapError::new(BAD_REQUEST, vap_error::INVALID_LANGUAGE).with_object(lang.to_string())),
+    }
+}
+
+fn duplicated<S: Into<String>>(name: S) -> VapError {
+    VapError::new(BAD_REQUEST, vap_error::DUPLICATED_NAME).with_object(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::msg_register_intents::*;
+
+    fn msg(slot_entity: &str, utterance: &str) -> MsgRegisterIntents {
+        MsgRegisterIntents {
+            skill_id: "com.example.test".into(),
+            nlu_data: vec![NluData {
+                language: Language {
+                    country: Some("US".into()),
+                    language: "en".into(),
+                    script: None,
+                    variants: vec![],
+                },
+                intents: vec![NluDataIntent {
+                    name: "turn_on".into(),
+                    utterances: vec![NluDataIntentUtterance {
+                        text: utterance.into(),
+                    }],
+                    slots: vec![NluDataSlot {
+                        name: "device".into(),
+                        entity: slot_entity.into(),
+                        required: true,
+                        prompt: Some("Which device?".into()),
+                        reprompt: None,
+                    }],
+                }],
+                entities: vec![NluDataEntity {
+                    name: "devices".into(),
+                    strict: false,
+                    data: vec![],
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn accepts_coherent_data() {
+        assert_eq!(
+            validate_register_intents(&msg("devices", "turn on the {device}")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reports_missing_entity() {
+        let err = validate_register_intents(&msg("lights", "turn on the {device}")).unwrap_err();
+        assert_eq!(err.code, 408);
+        assert_eq!(err.type_, vap_error::MISSING_ENTITY);
+        assert_eq!(err.object.as_deref(), Some("lights"));
+    }
+
+    #[test]
+    fn reports_missing_slot() {
+        let err = validate_register_intents(&msg("devices", "turn on the { room }")).unwrap_err();
+        assert_eq!(err.type_, vap_error::MISSING_SLOT);
+        assert_eq!(err.object.as_deref(), Some("room"));
+    }
+
+    #[test]
+    fn reports_duplicated_language() {
+        let mut m = msg("devices", "hi");
+        m.nlu_data.push(m.nlu_data[0].clone());
+        let err = validate_register_intents(&m).unwrap_err();
+        assert_eq!(err.type_, vap_error::DUPLICATED_NAME);
+        assert_eq!(err.object.as_deref(), Some("en-US"));
+    }
+
+    #[test]
+    fn reports_invalid_language() {
+        let mut m = msg("devices", "hi");
+        m.nlu_data[0].language.language = "not a language".into();
+        let err = validate_register_intents(&m).unwrap_err();
+        assert_eq!(err.code, 400);
+        assert_eq!(err.type_, vap_error::INVALID_LANGUAGE);
+    }
+}
@@ -0,0 +1,148 @@
+//! Versions of the protocol itself, and how two parts agree on which one to use.
+
+use std::{cmp, fmt::Display, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The newest VAP version implemented by this crate
+pub const VAP_VERSION: Version = Version::new(0, 2, 0);
+/// The oldest VAP version this crate can still talk to
+pub const MIN_VAP_VERSION: Version = Version::new(0, 1, 0);
+
+/// A semantic version of the protocol (major.minor.patch), sent as a string
+/// like "0.2.0". "Alpha", used before versions were semantic, is read as 0.1.0.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionParseError(String);
+
+impl Display for VersionParseError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "\"{}\" is not a valid VAP version", self.0)
+    }
+}
+
+impl std::error::Error for VersionParseError {}
+
+impl FromStr for Version {
+    type Err = VersionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "Alpha" {
+            return Ok(Version::new(0, 1, 0));
+        }
+
+        let mut parts = [0; 3];
+        for (i, part) in s.split('.').enumerate() {
+            if i == parts.len() {
+                return Err(VersionParseError(s.to_string()));
+            }
+
+            parts[i] = part.parse().map_err(|_| VersionParseError(s.to_string()))?;
+        }
+
+        Ok(Version::new(parts[0], parts[1], parts[2]))
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// All the versions between `min` and `max` (both included)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VersionRange {
+    pub min: Version,
+    pub max: Version,
+}
+
+impl VersionRange {
+    pub const fn new(min: Version, max: Version) -> Self {
+        VersionRange { min, max }
+    }
+
+    pub fn contains(&self, version: Version) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// The newest version supported by both ranges, if any
+    pub fn negotiate(&self, other: &VersionRange) -> Option<Version> {
+        let best = cmp::min(self.max, other.max);
+        if best >= cmp::max(self.min, other.min) {
+            Some(best)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for VersionRange {
+    fn default() -> Self {
+        VersionRange::new(MIN_VAP_VERSION, VAP_VERSION)
+    }
+}
+
+/// Written as "min-max" (e.g: "0.1.0-0.2.0")
+impl Display for VersionRange {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{}-{}", self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!("1.2.3".parse(), Ok(Version::new(1, 2, 3)));
+        assert_eq!("1.2".parse(), Ok(Version::new(1, 2, 0)));
+        assert_eq!("Alpha".parse(), Ok(Version::new(0, 1, 0)));
+        assert!("1.2.3.4".parse::<Version>().is_err());
+        assert!("one".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn negotiates_newest_common_version() {
+        let registry = VersionRange::new(Version::new(0, 1, 0), Version::new(0, 3, 0));
+        let skill = VersionRange::new(Version::new(0, 2, 0), Version::new(0, 4, 0));
+        assert_eq!(registry.negotiate(&skill), Some(Version::new(0, 3, 0)));
+
+        let old = VersionRange::new(Version::new(0, 1, 0), Version::new(0, 1, 0));
+        assert_eq!(registry.negotiate(&old), Some(Version::new(0, 1, 0)));
+
+        let newer = VersionRange::new(Version::new(1, 0, 0), Version::new(1, 1, 0));
+        assert_eq!(registry.negotiate(&newer), None);
+    }
+}
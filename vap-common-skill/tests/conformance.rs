@@ -0,0 +1,257 @@
+//! Checks the structures against the golden vectors in `protocol/conformance`,
+//! every vector must decode into it's message and encode back into the same bytes.
+
+use std::fmt;
+
+use serde::de::{DeserializeOwned, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use vap_common_skill::{encoding, structures::*};
+
+const SKILL_VECTORS: &str = include_str!("../../protocol/conformance/skills.json");
+const CLIENT_VECTORS: &str = include_str!("../../protocol/conformance/clients.json");
+
+#[derive(Deserialize)]
+struct Vector {
+    name: String,
+    message: String,
+    value: serde_json::Value,
+    msgpack: String,
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn check<T: DeserializeOwned + Serialize>(vector: &Vector) {
+    let bytes = from_hex(&vector.msgpack);
+
+    let msg: T = encoding::from_slice(&bytes)
+        .unwrap_or_else(|e| panic!("{}: could not decode: {}", vector.name, e));
+    assert_eq!(
+        encoding::to_vec(&msg).unwrap(),
+        bytes,
+        "{}: was not encoded back into the same bytes",
+        vector.name
+    );
+
+    // The readable form must be the same message
+    let msg: T = serde_json::from_value(vector.value.clone())
+        .unwrap_or_else(|e| panic!("{}: value is not the message: {}", vector.name, e));
+    assert_eq!(
+        encoding::to_vec(&msg).unwrap(),
+        bytes,
+        "{}: value and msgpack differ",
+        vector.name
+    );
+}
+
+#[test]
+fn skill_vectors() {
+    let vectors: Vec<Vector> = serde_json::from_str(SKILL_VECTORS).unwrap();
+    let mut tested = vec![];
+
+    for vector in &vectors {
+        match vector.message.as_str() {
+            "connect" => check::<MsgConnect>(vector),
+            "connectResponse" => check::<MsgConnectResponse>(vector),
+            "registerIntents" => check::<MsgRegisterIntents>(vector),
+            "registerIntentsResponse" => check::<MsgRegisterIntentsResponse>(vector),
+            "skillRequest" => check::<MsgSkillRequest>(vector),
+            "notification" => check::<MsgNotification>(vector),
+            "notificationResponse" => check::<MsgNotificationResponse>(vector),
+            "query" => check::<MsgQuery>(vector),
+            "queryResponse" => check::<MsgQueryResponse>(vector),
+            "skillClose" => check::<MsgSkillClose>(vector),
+            "heartbeat" => check::<MsgHeartbeat>(vector),
+            "error" => check::<VapError>(vector),
+            other => panic!("{}: unknown message {}", vector.name, other),
+        }
+
+        tested.push(vector.message.as_str());
+    }
+
+    // Every message should have at least a vector
+    for message in [
+        "connect",
+        "connectResponse",
+        "registerIntents",
+        "registerIntentsResponse",
+        "skillRequest",
+        "notification",
+        "notificationResponse",
+        "query",
+        "queryResponse",
+        "skillClose",
+        "heartbeat",
+        "error",
+    ] {
+        assert!(tested.contains(&message), "No vector for {}", message);
+    }
+}
+
+/// Any MsgPack value. Unlike `Value` maps keep their order, so that it can be
+/// encoded back into the same bytes.
+#[derive(Debug)]
+enum Raw {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Binary(Vec<u8>),
+    Array(Vec<Raw>),
+    Map(Vec<(Raw, Raw)>),
+}
+
+impl Raw {
+    /// The readable form, binaries have none
+    fn to_json(&self) -> serde_json::Value {
+        use serde_json::Value as Json;
+        match self {
+            Raw::Nil | Raw::Binary(_) => Json::Null,
+            Raw::Bool(b) => Json::from(*b),
+            Raw::Int(i) => Json::from(*i),
+            Raw::UInt(u) => Json::from(*u),
+            Raw::F32(f) => Json::from(*f),
+            Raw::F64(f) => Json::from(*f),
+            Raw::String(s) => Json::from(s.as_str()),
+            Raw::Array(a) => Json::Array(a.iter().map(Raw::to_json).collect()),
+            Raw::Map(m) => Json::Object(
+                m.iter()
+                    .map(|(k, v)| match k {
+                        Raw::String(k) => (k.clone(), v.to_json()),
+                        k => (format!("{:?}", k), v.to_json()),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Raw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawVisitor;
+
+        impl<'de> Visitor<'de> for RawVisitor {
+            type Value = Raw;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("any MsgPack value")
+            }
+
+            fn visit_unit<E>(self) -> Result<Raw, E> {
+                Ok(Raw::Nil)
+            }
+
+            fn visit_none<E>(self) -> Result<Raw, E> {
+                Ok(Raw::Nil)
+            }
+
+            fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Raw, D::Error> {
+                Raw::deserialize(d)
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Raw, E> {
+                Ok(Raw::Bool(v))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Raw, E> {
+                Ok(Raw::Int(v))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Raw, E> {
+                Ok(Raw::UInt(v))
+            }
+
+            fn visit_f32<E>(self, v: f32) -> Result<Raw, E> {
+                Ok(Raw::F32(v))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Raw, E> {
+                Ok(Raw::F64(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Raw, E> {
+                Ok(Raw::String(v.to_string()))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Raw, E> {
+                Ok(Raw::Binary(v.to_vec()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Raw, A::Error> {
+                let mut values = vec![];
+                while let Some(v) = seq.next_element()? {
+                    values.push(v);
+                }
+                Ok(Raw::Array(values))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Raw, A::Error> {
+                let mut entries = vec![];
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Raw::Map(entries))
+            }
+        }
+
+        deserializer.deserialize_any(RawVisitor)
+    }
+}
+
+impl Serialize for Raw {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Raw::Nil => serializer.serialize_unit(),
+            Raw::Bool(b) => serializer.serialize_bool(*b),
+            Raw::Int(i) => serializer.serialize_i64(*i),
+            Raw::UInt(u) => serializer.serialize_u64(*u),
+            Raw::F32(f) => serializer.serialize_f32(*f),
+            Raw::F64(f) => serializer.serialize_f64(*f),
+            Raw::String(s) => serializer.serialize_str(s),
+            Raw::Binary(b) => serializer.serialize_bytes(b),
+            Raw::Array(a) => {
+                let mut seq = serializer.serialize_seq(Some(a.len()))?;
+                for v in a {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Raw::Map(m) => {
+                let mut map = serializer.serialize_map(Some(m.len()))?;
+                for (k, v) in m {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// There are no client structures yet, so client vectors are only checked to
+/// be valid MsgPack that encodes back into the same bytes, and matches its value
+#[test]
+fn client_vectors() {
+    let vectors: Vec<Vector> = serde_json::from_str(CLIENT_VECTORS).unwrap();
+    assert!(!vectors.is_empty());
+
+    for vector in &vectors {
+        let bytes = from_hex(&vector.msgpack);
+        let raw: Raw = encoding::from_slice(&bytes)
+            .unwrap_or_else(|e| panic!("{}: could not decode: {}", vector.name, e));
+        assert_eq!(
+            encoding::to_vec(&raw).unwrap(),
+            bytes,
+            "{}: was not encoded back into the same bytes",
+            vector.name
+        );
+        assert_eq!(raw.to_json(), vector.value, "{}: value and msgpack differ", vector.name);
+    }
+}
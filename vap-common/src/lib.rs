@@ -1,5 +1,9 @@
+#![cfg_attr(not(test), no_std)]
 #![allow(dead_code)]
+extern crate alloc;
+
 pub mod capability;
+pub mod oscore;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,709 @@
+//! OSCORE (RFC 8613): protects CoAP messages end to end, whatever carries them.
+//! Uses AES-CCM-16-64-128 and HKDF-SHA-256, the algorithms every implementation
+//! has. Works on whole messages in their wire format, so that it doesn't matter
+//! which CoAP library made them, and only needs `core` and `alloc`.
+//!
+//! Errors map to the answers of RFC 8613 section 8.2: `Malformed` is a 4.02 Bad
+//! Option, `UnknownContext` and `Replayed` a 4.01 Unauthorized and `Decryption`
+//! a 4.00 Bad Request, those answers are sent unprotected.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use aes::Aes128;
+use ccm::aead::{generic_array::GenericArray, AeadInPlace, KeyInit};
+use ccm::consts::{U13, U8};
+use ccm::Ccm;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// The number of the OSCORE option
+pub const OPTION: u16 = 9;
+
+/// The longest sender and recipient ids, longer ones don't fit in the nonce
+pub const MAX_ID_LEN: usize = NONCE_LEN - 6;
+
+type AesCcm = Ccm<Aes128, U8, U13>;
+
+/// AES-CCM-16-64-128, as a COSE algorithm
+const ALG_AEAD: u8 = 10;
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 13;
+const TAG_LEN: usize = 8;
+/// The biggest sequence number, Partial IVs are 5 bytes at most
+const MAX_SEQUENCE: u64 = (1 << 40) - 1;
+/// How many of the sequence numbers below the newest are still accepted
+const REPLAY_WINDOW: u64 = 32;
+
+const PAYLOAD_MARKER: u8 = 0xFF;
+const URI_HOST: u16 = 3;
+const OBSERVE: u16 = 6;
+const URI_PORT: u16 = 7;
+const PROXY_URI: u16 = 35;
+const PROXY_SCHEME: u16 = 39;
+/// Outer codes of protected messages (POST, FETCH, 2.04 Changed and 2.05 Content)
+const POST: u8 = 0x02;
+const FETCH: u8 = 0x05;
+const CHANGED: u8 = 0x44;
+const CONTENT: u8 = 0x45;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Not a CoAP message, or its OSCORE option can't be read
+    Malformed,
+    /// The message doesn't have an OSCORE option
+    NotProtected,
+    /// The message was protected with another security context
+    UnknownContext,
+    /// The message was received before
+    Replayed,
+    /// The message was altered or protected with other keys
+    Decryption,
+    /// Every sequence number was used, a new security context is needed
+    Exhausted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Error::Malformed => "malformed message",
+            Error::NotProtected => "the message is not protected",
+            Error::UnknownContext => "unknown security context",
+            Error::Replayed => "the message was replayed",
+            Error::Decryption => "the message could not be decrypted",
+            Error::Exhausted => "no sequence numbers left",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Both ends of an OSCORE conversation, an endpoint's sender is the other
+/// endpoint's recipient
+pub struct SecurityContext {
+    id_context: Option<Vec<u8>>,
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    sender_key: [u8; KEY_LEN],
+    recipient_key: [u8; KEY_LEN],
+    common_iv: [u8; NONCE_LEN],
+    sender_sequence: u64,
+    replay: ReplayWindow,
+}
+
+/// What a response needs from the request it answers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestRef {
+    kid: Vec<u8>,
+    partial_iv: Vec<u8>,
+}
+
+impl SecurityContext {
+    /// Derives the keys, the sequence number starts at 0. Never derive the
+    /// same context twice (e.g: use a new `id_context` each time), sequence
+    /// numbers would be used again with the same keys.
+    ///
+    /// # Panics
+    /// If an id is longer than `MAX_ID_LEN`
+    pub fn new(
+        master_secret: &[u8],
+        master_salt: &[u8],
+        id_context: Option<&[u8]>,
+        sender_id: &[u8],
+        recipient_id: &[u8],
+    ) -> Self {
+        assert!(sender_id.len() <= MAX_ID_LEN && recipient_id.len() <= MAX_ID_LEN);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(master_salt), master_secret);
+        let derive = |id: &[u8], type_: &str, out: &mut [u8]| {
+            let mut info = Vec::new();
+            cbor_head(&mut info, 4, 5);
+            cbor_bytes(&mut info, id);
+            match id_context {
+                Some(c) => cbor_bytes(&mut info, c),
+                None => info.push(0xF6),
+            }
+            cbor_head(&mut info, 0, u64::from(ALG_AEAD));
+            cbor_head(&mut info, 3, type_.len() as u64);
+            info.extend_from_slice(type_.as_bytes());
+            cbor_head(&mut info, 0, out.len() as u64);
+            hkdf.expand(&info, out).expect("Small enough for HKDF");
+        };
+
+        let mut sender_key = [0; KEY_LEN];
+        let mut recipient_key = [0; KEY_LEN];
+        let mut common_iv = [0; NONCE_LEN];
+        derive(sender_id, "Key", &mut sender_key);
+        derive(recipient_id, "Key", &mut recipient_key);
+        derive(&[], "IV", &mut common_iv);
+
+        SecurityContext {
+            id_context: id_context.map(Vec::from),
+            sender_id: sender_id.into(),
+            recipient_id: recipient_id.into(),
+            sender_key,
+            recipient_key,
+            common_iv,
+            sender_sequence: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    pub fn id_context(&self) -> Option<&[u8]> {
+        self.id_context.as_deref()
+    }
+
+    /// Protects a request, keep the `RequestRef` to read its response. The ID
+    /// Context is sent along, so that the other end can find this context.
+    pub fn protect_request(&mut self, request: &[u8]) -> Result<(Vec<u8>, RequestRef), Error> {
+        let message = Message::parse(request)?;
+        let partial_iv = self.next_partial_iv()?;
+        let option = OscoreOption {
+            partial_iv: Some(partial_iv.clone()),
+            kid_context: self.id_context.clone(),
+            kid: Some(self.sender_id.clone()),
+        };
+        let request_ref = RequestRef {kid: self.sender_id.clone(), partial_iv};
+        let nonce = self.nonce(&request_ref.kid, &request_ref.partial_iv);
+        let outer_code = if message.option(OBSERVE).is_some() { FETCH } else { POST };
+        let protected = self.seal(message, &nonce, &request_ref, option, outer_code, true);
+        Ok((protected, request_ref))
+    }
+
+    /// Reads a protected request, keep the `RequestRef` to protect its response
+    pub fn unprotect_request(&mut self, request: &[u8]) -> Result<(Vec<u8>, RequestRef), Error> {
+        let message = Message::parse(request)?;
+        let option = message.oscore_option()?;
+        let (Some(partial_iv), Some(kid)) = (option.partial_iv, option.kid) else {
+            return Err(Error::Malformed);
+        };
+        if kid != self.recipient_id || option.kid_context.as_deref() != self.id_context() {
+            return Err(Error::UnknownContext);
+        }
+        let sequence = partial_iv.iter().fold(0, |n, b| (n << 8) | u64::from(*b));
+        if !self.replay.is_new(sequence) {
+            return Err(Error::Replayed);
+        }
+
+        let request_ref = RequestRef {kid, partial_iv};
+        let nonce = self.nonce(&request_ref.kid, &request_ref.partial_iv);
+        let plain = self.open(message, &nonce, &request_ref, true)?;
+        // Only once it is known to be authentic
+        self.replay.mark(sequence);
+        Ok((plain, request_ref))
+    }
+
+    /// Protects the response to a request. Notifications (responses with
+    /// Observe) get a Partial IV of their own, as every one of them is new.
+    pub fn protect_response(&mut self, response: &[u8], request: &RequestRef) -> Result<Vec<u8>, Error> {
+        let message = Message::parse(response)?;
+        let (option, nonce, outer_code) = if message.option(OBSERVE).is_some() {
+            let partial_iv = self.next_partial_iv()?;
+            let nonce = self.nonce(&self.sender_id, &partial_iv);
+            (OscoreOption {partial_iv: Some(partial_iv), ..Default::default()}, nonce, CONTENT)
+        }
+        else {
+            (OscoreOption::default(), self.nonce(&request.kid, &request.partial_iv), CHANGED)
+        };
+        Ok(self.seal(message, &nonce, request, option, outer_code, false))
+    }
+
+    /// Reads the protected response to a request
+    pub fn unprotect_response(&mut self, response: &[u8], request: &RequestRef) -> Result<Vec<u8>, Error> {
+        let message = Message::parse(response)?;
+        let option = message.oscore_option()?;
+        let nonce = match &option.partial_iv {
+            Some(partial_iv) => self.nonce(&self.recipient_id, partial_iv),
+            None => self.nonce(&request.kid, &request.partial_iv),
+        };
+        self.open(message, &nonce, request, false)
+    }
+
+    fn next_partial_iv(&mut self) -> Result<Vec<u8>, Error> {
+        if self.sender_sequence > MAX_SEQUENCE {
+            return Err(Error::Exhausted);
+        }
+        let sequence = self.sender_sequence;
+        self.sender_sequence += 1;
+
+        // As short as possible, but never empty
+        let bytes = sequence.to_be_bytes();
+        let first = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len() - 1);
+        Ok(bytes[first..].to_vec())
+    }
+
+    /// The nonce for a Partial IV, `id` is the sender id of whoever made it
+    fn nonce(&self, id: &[u8], partial_iv: &[u8]) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[0] = id.len() as u8;
+        nonce[NONCE_LEN - 5 - id.len()..NONCE_LEN - 5].copy_from_slice(id);
+        nonce[NONCE_LEN - partial_iv.len()..].copy_from_slice(partial_iv);
+        for (n, iv) in nonce.iter_mut().zip(self.common_iv.iter()) {
+            *n ^= iv;
+        }
+        nonce
+    }
+
+    fn seal(
+        &self,
+        message: Message,
+        nonce: &[u8; NONCE_LEN],
+        request: &RequestRef,
+        option: OscoreOption,
+        outer_code: u8,
+        is_request: bool,
+    ) -> Vec<u8> {
+        let (inner, outer): (Vec<_>, Vec<_>) = message.options.into_iter()
+            .partition(|(n, _)| is_inner(*n, is_request));
+
+        let mut plain = vec![message.code];
+        write_options(&mut plain, &inner);
+        if !message.payload.is_empty() {
+            plain.push(PAYLOAD_MARKER);
+            plain.extend_from_slice(&message.payload);
+        }
+
+        let cipher = AesCcm::new(GenericArray::from_slice(&self.sender_key));
+        let tag = cipher
+            .encrypt_in_place_detached(GenericArray::from_slice(nonce), &aad(request), &mut plain)
+            .expect("Messages are far smaller than what CCM can take");
+        plain.extend_from_slice(&tag);
+
+        let mut options: Vec<_> = outer.into_iter()
+            .filter(|(n, _)| is_outer(*n, is_request))
+            .collect();
+        options.push((OPTION, option.encode()));
+        options.sort_by_key(|(n, _)| *n);
+        Message {
+            code: outer_code,
+            options,
+            payload: plain,
+            ..message
+        }.to_bytes()
+    }
+
+    fn open(
+        &self,
+        message: Message,
+        nonce: &[u8; NONCE_LEN],
+        request: &RequestRef,
+        is_request: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let mut plain = message.payload;
+        if plain.len() < TAG_LEN + 1 {
+            return Err(Error::Decryption);
+        }
+        let tag = plain.split_off(plain.len() - TAG_LEN);
+        let cipher = AesCcm::new(GenericArray::from_slice(&self.recipient_key));
+        cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(nonce),
+                &aad(request),
+                &mut plain,
+                GenericArray::from_slice(&tag),
+            )
+            .map_err(|_| Error::Decryption)?;
+
+        // The code, then the inner options and the payload, as in a message
+        let (inner, payload) = Message::parse_options(&plain[1..])?;
+        let mut options: Vec<_> = message.options.into_iter()
+            .filter(|(n, _)| *n != OPTION && !(is_request && inner.iter().any(|(i, _)| i == n)))
+            .collect();
+        options.extend(inner);
+        options.sort_by_key(|(n, _)| *n);
+        Ok(Message {
+            code: plain[0],
+            options,
+            payload,
+            ..message
+        }.to_bytes())
+    }
+}
+
+impl fmt::Debug for SecurityContext {
+    // Keys stay out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecurityContext")
+            .field("id_context", &self.id_context)
+            .field("sender_id", &self.sender_id)
+            .field("recipient_id", &self.recipient_id)
+            .field("sender_sequence", &self.sender_sequence)
+            .finish()
+    }
+}
+
+/// The ID Context a protected request was sent with, to find out its security
+/// context. `Ok(None)` if it was sent without one.
+pub fn request_id_context(request: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    Ok(Message::parse(request)?.oscore_option()?.kid_context)
+}
+
+/// Options in the encrypted part, the rest stay outside so that proxies can
+/// read them. Observe is in both places in requests and only outside in
+/// responses.
+fn is_inner(number: u16, is_request: bool) -> bool {
+    !matches!(number, URI_HOST | URI_PORT | PROXY_URI | PROXY_SCHEME | OPTION)
+        && (is_request || number != OBSERVE)
+}
+
+fn is_outer(number: u16, is_request: bool) -> bool {
+    !is_inner(number, is_request) || number == OBSERVE
+}
+
+/// The additional authenticated data, the same for a request and its responses
+fn aad(request: &RequestRef) -> Vec<u8> {
+    let mut external = Vec::new();
+    cbor_head(&mut external, 4, 5);
+    external.push(1); // OSCORE version
+    cbor_head(&mut external, 4, 1);
+    cbor_head(&mut external, 0, u64::from(ALG_AEAD));
+    cbor_bytes(&mut external, &request.kid);
+    cbor_bytes(&mut external, &request.partial_iv);
+    cbor_bytes(&mut external, &[]); // No Class I options
+
+    let mut aad = Vec::new();
+    cbor_head(&mut aad, 4, 3);
+    cbor_head(&mut aad, 3, 8);
+    aad.extend_from_slice(b"Encrypt0");
+    cbor_bytes(&mut aad, &[]);
+    cbor_bytes(&mut aad, &external);
+    aad
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xFF => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xFFFF => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// The value of the OSCORE option
+#[derive(Debug, Default, PartialEq, Eq)]
+struct OscoreOption {
+    partial_iv: Option<Vec<u8>>,
+    kid_context: Option<Vec<u8>>,
+    kid: Option<Vec<u8>>,
+}
+
+impl OscoreOption {
+    const KID_CONTEXT: u8 = 0x10;
+    const KID: u8 = 0x08;
+
+    fn decode(value: &[u8]) -> Result<Self, Error> {
+        let Some((&flags, mut rest)) = value.split_first() else {
+            return Ok(OscoreOption::default());
+        };
+        let piv_len = usize::from(flags & 0x07);
+        // Reserved flags, and Partial IVs longer than 5
+        if flags & 0xE0 != 0 || piv_len > 5 || rest.len() < piv_len {
+            return Err(Error::Malformed);
+        }
+        let (partial_iv, after) = rest.split_at(piv_len);
+        rest = after;
+
+        let mut option = OscoreOption {
+            partial_iv: (piv_len > 0).then(|| partial_iv.to_vec()),
+            ..Default::default()
+        };
+        if flags & Self::KID_CONTEXT != 0 {
+            let (&len, after) = rest.split_first().ok_or(Error::Malformed)?;
+            let len = usize::from(len);
+            if after.len() < len {
+                return Err(Error::Malformed);
+            }
+            option.kid_context = Some(after[..len].to_vec());
+            rest = &after[len..];
+        }
+        if flags & Self::KID != 0 {
+            option.kid = Some(rest.to_vec());
+        }
+        else if !rest.is_empty() {
+            return Err(Error::Malformed);
+        }
+        Ok(option)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let partial_iv = self.partial_iv.as_deref().unwrap_or_default();
+        let mut flags = partial_iv.len() as u8;
+        let mut value = vec![0];
+        value.extend_from_slice(partial_iv);
+        if let Some(kid_context) = &self.kid_context {
+            flags |= Self::KID_CONTEXT;
+            value.push(kid_context.len() as u8);
+            value.extend_from_slice(kid_context);
+        }
+        if let Some(kid) = &self.kid {
+            flags |= Self::KID;
+            value.extend_from_slice(kid);
+        }
+        if flags == 0 {
+            return Vec::new();
+        }
+        value[0] = flags;
+        value
+    }
+}
+
+/// A CoAP message, just enough of it to move options in and out
+struct Message {
+    /// Version, type and token length, then the message id
+    head: [u8; 3],
+    code: u8,
+    token: Vec<u8>,
+    /// Sorted by number
+    options: Vec<(u16, Vec<u8>)>,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 4 || bytes[0] >> 6 != 1 {
+            return Err(Error::Malformed);
+        }
+        let token_len = usize::from(bytes[0] & 0x0F);
+        if token_len > 8 || bytes.len() < 4 + token_len {
+            return Err(Error::Malformed);
+        }
+        let (options, payload) = Self::parse_options(&bytes[4 + token_len..])?;
+        Ok(Message {
+            head: [bytes[0], bytes[2], bytes[3]],
+            code: bytes[1],
+            token: bytes[4..4 + token_len].to_vec(),
+            options,
+            payload,
+        })
+    }
+
+    /// Options until the payload marker, then the payload
+    #[allow(clippy::type_complexity)]
+    fn parse_options(mut bytes: &[u8]) -> Result<(Vec<(u16, Vec<u8>)>, Vec<u8>), Error> {
+        fn extended(nibble: u8, bytes: &mut &[u8]) -> Result<usize, Error> {
+            let (value, len) = match nibble {
+                13 => (usize::from(*bytes.first().ok_or(Error::Malformed)?) + 13, 1),
+                14 => {
+                    let b = bytes.get(..2).ok_or(Error::Malformed)?;
+                    (usize::from(u16::from_be_bytes([b[0], b[1]])) + 269, 2)
+                }
+                15 => return Err(Error::Malformed),
+                n => (usize::from(n), 0),
+            };
+            *bytes = &bytes[len..];
+            Ok(value)
+        }
+
+        let mut options = Vec::new();
+        let mut number = 0usize;
+        while let Some((&first, rest)) = bytes.split_first() {
+            bytes = rest;
+            if first == PAYLOAD_MARKER {
+                if bytes.is_empty() {
+                    return Err(Error::Malformed);
+                }
+                return Ok((options, bytes.to_vec()));
+            }
+            number += extended(first >> 4, &mut bytes)?;
+            let len = extended(first & 0x0F, &mut bytes)?;
+            if bytes.len() < len || number > usize::from(u16::MAX) {
+                return Err(Error::Malformed);
+            }
+            options.push((number as u16, bytes[..len].to_vec()));
+            bytes = &bytes[len..];
+        }
+        Ok((options, Vec::new()))
+    }
+
+    fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options.iter().find(|(n, _)| *n == number).map(|(_, v)| v.as_slice())
+    }
+
+    fn oscore_option(&self) -> Result<OscoreOption, Error> {
+        OscoreOption::decode(self.option(OPTION).ok_or(Error::NotProtected)?)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.head[0], self.code, self.head[1], self.head[2]];
+        bytes.extend_from_slice(&self.token);
+        write_options(&mut bytes, &self.options);
+        if !self.payload.is_empty() {
+            bytes.push(PAYLOAD_MARKER);
+            bytes.extend_from_slice(&self.payload);
+        }
+        bytes
+    }
+}
+
+/// Writes options sorted by number, as deltas
+fn write_options(out: &mut Vec<u8>, options: &[(u16, Vec<u8>)]) {
+    fn nibble(value: usize, extended: &mut Vec<u8>) -> u8 {
+        match value {
+            0..=12 => value as u8,
+            13..=268 => {
+                extended.push((value - 13) as u8);
+                13
+            }
+            _ => {
+                extended.extend_from_slice(&((value - 269) as u16).to_be_bytes());
+                14
+            }
+        }
+    }
+
+    let mut last = 0;
+    for (number, value) in options {
+        let mut extended = Vec::new();
+        let delta = nibble(usize::from(number - last), &mut extended);
+        let len = nibble(value.len(), &mut extended);
+        out.push(delta << 4 | len);
+        out.extend_from_slice(&extended);
+        out.extend_from_slice(value);
+        last = *number;
+    }
+}
+
+/// Which sequence numbers of the other end were seen, the newest and the
+/// `REPLAY_WINDOW` before it
+#[derive(Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    /// Bit n is the sequence number `newest - n`
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, sequence: u64) -> bool {
+        match self.newest {
+            None => true,
+            Some(newest) if sequence > newest => true,
+            Some(newest) => newest - sequence < REPLAY_WINDOW && self.seen & (1 << (newest - sequence)) == 0,
+        }
+    }
+
+    fn mark(&mut self, sequence: u64) {
+        match self.newest {
+            Some(newest) if sequence <= newest => self.seen |= 1 << (newest - sequence),
+            newest => {
+                let shift = newest.map_or(REPLAY_WINDOW, |n| sequence - n);
+                self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.newest = Some(sequence);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// The contexts of RFC 8613 appendix C.1
+    fn client_and_server() -> (SecurityContext, SecurityContext) {
+        let secret = hex("0102030405060708090a0b0c0d0e0f10");
+        let salt = hex("9e7ca92223786340");
+        (
+            SecurityContext::new(&secret, &salt, None, &[], &[1]),
+            SecurityContext::new(&secret, &salt, None, &[1], &[]),
+        )
+    }
+
+    #[test]
+    fn derives_the_keys_of_the_rfc() {
+        let (client, server) = client_and_server();
+        assert_eq!(client.sender_key[..], hex("f0910ed7295e6ad4b54fc793154302ff")[..]);
+        assert_eq!(client.recipient_key[..], hex("ffb14e093c94c9cac9471648b4f98710")[..]);
+        assert_eq!(client.common_iv[..], hex("4622d4dd6d944168eefb54987c")[..]);
+        assert_eq!(server.sender_key, client.recipient_key);
+        assert_eq!(server.common_iv, client.common_iv);
+    }
+
+    #[test]
+    fn protects_like_the_rfc() {
+        let (mut client, mut server) = client_and_server();
+        client.sender_sequence = 20;
+
+        // Test vector 4: GET coap://localhost/tv1
+        let request = hex("44015d1f00003974396c6f63616c686f737483747631");
+        let (protected, request_ref) = client.protect_request(&request).unwrap();
+        assert_eq!(protected, hex("44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e"));
+
+        let (unprotected, server_ref) = server.unprotect_request(&protected).unwrap();
+        assert_eq!(unprotected, request);
+        assert_eq!(server_ref, request_ref);
+
+        // Test vector 7: 2.05 Content "Hello World!"
+        let response = hex("64455d1f00003974ff48656c6c6f20576f726c6421");
+        let protected = server.protect_response(&response, &server_ref).unwrap();
+        assert_eq!(protected, hex("64445d1f0000397490ffdbaad1e9a7e7b2a813d3c31524378303cdafae119106"));
+        assert_eq!(client.unprotect_response(&protected, &request_ref).unwrap(), response);
+    }
+
+    #[test]
+    fn rejects_replays_and_other_contexts() {
+        let (mut client, mut server) = client_and_server();
+        let request = hex("44015d1f00003974396c6f63616c686f737483747631");
+        let (first, _) = client.protect_request(&request).unwrap();
+        let (second, _) = client.protect_request(&request).unwrap();
+
+        assert!(server.unprotect_request(&second).is_ok());
+        // Older, but not seen yet
+        assert!(server.unprotect_request(&first).is_ok());
+        assert_eq!(server.unprotect_request(&first), Err(Error::Replayed));
+        assert_eq!(server.unprotect_request(&request), Err(Error::NotProtected));
+
+        let mut altered = second.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        let (third, _) = client.protect_request(&request).unwrap();
+        assert_eq!(server.unprotect_request(&altered), Err(Error::Replayed));
+        let mut altered = third.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(server.unprotect_request(&altered), Err(Error::Decryption));
+        // A failed one doesn't use up its sequence number
+        assert!(server.unprotect_request(&third).is_ok());
+
+        let secret = hex("0102030405060708090a0b0c0d0e0f10");
+        let mut other = SecurityContext::new(&secret, &[], Some(b"other"), &[], &[1]);
+        assert_eq!(other.unprotect_request(&third), Err(Error::UnknownContext));
+    }
+
+    #[test]
+    fn notifications_have_their_own_partial_iv() {
+        let secret = b"token";
+        let mut skill = SecurityContext::new(secret, &[], Some(b"context"), &[1], &[]);
+        let mut registry = SecurityContext::new(secret, &[], Some(b"context"), &[], &[1]);
+
+        // GET with Observe: 0 and a Uri-Path "skills"
+        let observe = hex("41011234aa6056736b696c6c73");
+        let (protected, skill_ref) = skill.protect_request(&observe).unwrap();
+        assert_eq!(request_id_context(&protected).unwrap().as_deref(), Some(&b"context"[..]));
+        let (request, registry_ref) = registry.unprotect_request(&protected).unwrap();
+        assert_eq!(request, observe);
+
+        // Two notifications (Observe: 1 and 2) with the same payload
+        let mut last = Vec::new();
+        for observe in 1..=2u8 {
+            let notification = [&hex("61451234aa61")[..], &[observe, 0xFF, b'h', b'i']].concat();
+            let protected = registry.protect_response(&notification, &registry_ref).unwrap();
+            assert_ne!(protected, last);
+            assert_eq!(skill.unprotect_response(&protected, &skill_ref).unwrap(), notification);
+            last = protected;
+        }
+    }
+}
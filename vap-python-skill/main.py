@@ -7,12 +7,15 @@ import re
 from typing import Optional
 
 import aiocoap
+import aiocoap.optiontypes
 import msgpack
 
 registry_address = "127.0.0.1"
 all_coaps_ip4 = "224.0.1.187"
 skill_id = "com.example.test"
 coap_host_reg = re.compile(r'^coap:\/\/([1-9.a-zA-Z]+)\/')
+# Every message after connect carries our token in this option
+auth_token_option = 65000
 
 def list_caps(payload):
     """Transform a list of capabilities into a string."""
@@ -26,6 +29,12 @@ class VapClient():
 
         self.client = await aiocoap.Context.create_client_context()
 
+    def __message(self, **kwargs):
+        """ A message for the registry, with our token so that it knows it's us """
+        message = aiocoap.Message(**kwargs)
+        message.opt.add_option(aiocoap.optiontypes.StringOption(auth_token_option, self.token))
+        return message
+
     async def __find_registry(self) -> Optional[str]:
         """ Find a registry using CoAP's discovery """
         request = aiocoap.Message(code=aiocoap.GET, uri=f'coap://{all_coaps_ip4}/.well-known/core?rt=vap-skill-registry')
@@ -46,7 +55,8 @@ class VapClient():
         payload = {
             "name": "My test skill",
             "id": skill_id,
-            "vapVersion": "Alpha"
+            "vapVersion": "0.2.0",
+            "minVapVersion": "0.1.0"
         }
 
         # Create message
@@ -60,19 +70,26 @@ class VapClient():
             raise Exception(f"Failed to register skill: {response.code}")
         
         resp_payload = msgpack.unpackb(response.payload)
-        langs_id = 0
+        # Needed to connect again if we are still regarded as connected
+        self.token = resp_payload.get('uniqueAuthenticationToken')
+        # Only present if the registry wants to know we are still alive
+        self.heartbeat_secs = resp_payload.get('heartbeatSecs')
         def lang_to_str(lang):
-            if not lang[0] is None:
-                first_phase = f'{lang[1]}-{lang[0]}'
-            else:
-                first_phase = lang[1]
-            
-            if not lang[2] is None:
-                return f'{first_phase}-{lang[2]}'
-            else:
-                return first_phase
+            # Languages can also be sent in their compact form ("en-US")
+            if isinstance(lang, str):
+                return lang
+
+            parts = [lang["language"]]
+            if not lang["script"] is None:
+                parts.append(lang["script"])
+
+            if not lang["country"] is None:
+                parts.append(lang["country"])
+
+            parts.extend(lang["variants"])
+            return '-'.join(parts)
 
-        print(f"Languages available: {','.join( [lang_to_str(x) for x in resp_payload[langs_id]])}")
+        print(f"Languages available: {','.join( [lang_to_str(x) for x in resp_payload['langs']])}")
 
     async def registerIntents(self):
         # Send our utterances to the server for them to be taken account of
@@ -83,8 +100,9 @@ class VapClient():
                 {
                     "language": {
                         "language": "en",
-                        "country": "us",
-                        "extra": None
+                        "country": "US",
+                        "script": None,
+                        "variants": []
                     },
                     "intents": [
                         {
@@ -101,8 +119,9 @@ class VapClient():
                 {
                     "language": {
                         "language": "en",
-                        "country": "es",
-                        "extra": None
+                        "country": "ES",
+                        "script": None,
+                        "variants": []
                     },
                     "intents": [
                         {
@@ -120,7 +139,7 @@ class VapClient():
         }
         
         # Create message
-        request = aiocoap.Message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/registerIntents')
+        request = self.__message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/registerIntents')
 
         response = await self.client.request(request).response
 
@@ -130,7 +149,7 @@ class VapClient():
     async def close(self):
         # We have finished, let it know to the server
 
-        request = aiocoap.Message(code=aiocoap.DELETE, uri=f'coap://{registry_address}/vap/skillRegistry/skills/{skill_id}')
+        request = self.__message(code=aiocoap.DELETE, payload=msgpack.packb({"skillId": skill_id}), uri=f'coap://{registry_address}/vap/skillRegistry/skills/{skill_id}')
 
         # Send it  to the registry and wait for a response
         response = await self.client.request(request).response
@@ -139,6 +158,21 @@ class VapClient():
         if response.code != aiocoap.DELETED:
             raise Exception(f"Failed to disconenct from registry: {response.code}")
 
+    async def heartbeats(self):
+        # Let the registry know we are alive, otherwise it will regard us as gone
+
+        if self.heartbeat_secs is None:
+            return
+
+        payload = msgpack.packb({"skillId": skill_id})
+        while True:
+            await asyncio.sleep(self.heartbeat_secs)
+            request = self.__message(code=aiocoap.POST, payload=payload, uri=f'coap://{registry_address}/vap/skillRegistry/heartbeat')
+            response = await self.client.request(request).response
+
+            if response.code != aiocoap.CHANGED:
+                raise Exception(f"Failed to send heartbeat: {response.code}")
+
     async def notification(self):
         """ Some request started by the skill, if the reception went well we'll
         have a "CONTENT" code. """
@@ -156,7 +190,7 @@ class VapClient():
         }
 
         # Create request
-        request = aiocoap.Message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/notification')
+        request = self.__message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/notification')
 
         # Send request
         response = await self.client.request(request).response
@@ -181,7 +215,7 @@ class VapClient():
         }
 
         # Create request
-        request = aiocoap.Message(code=aiocoap.GET, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/query')
+        request = self.__message(code=aiocoap.GET, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/query')
 
         response = await self.client.request(request).response
 
@@ -189,15 +223,12 @@ class VapClient():
         if response.code != aiocoap.CONTENT:
             raise Exception(f"Failed to disconenct from registry: {response.code}")
 
-        data_id = 0
-        capabilities_id = 1
-
         # Find the same capability, preferences, that we sent, remember we can 
         # receive multiple capabilities and multiple clients in a same response.
         # We find it by applying a filter
         cap_color = list(filter(
             lambda c: c["name"]=="preferences",
-            msgpack.unpackb(response.payload)[data_id][0][capabilities_id]))
+            msgpack.unpackb(response.payload)["data"][0]["capabilities"]))
 
         # Now that we have a list, get the first item and return the color that
         # we asked for
@@ -221,7 +252,7 @@ class VapClient():
         }
 
         # Create request
-        request = aiocoap.Message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/notification')
+        request = self.__message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/notification')
 
         # Send request
         response = await self.client.request(request).response
@@ -232,7 +263,7 @@ class VapClient():
 
     async def register(self):
         print(skill_id)
-        message = aiocoap.Message(
+        message = self.__message(
             code=aiocoap.GET,
             observe=0,
             uri=f'coap://{registry_address}/vap/skillRegistry/skills/{skill_id}'
@@ -243,18 +274,13 @@ class VapClient():
         print("Waiting for request...")
         async for r in request.observation:
             payload = msgpack.unpackb(r.payload, strict_map_key=False)
-            request_id = 2
-            type_id = 0
-            request_type = next(iter(payload[request_id][type_id]))
-            canAnswer = 2
-            intent = 0
+            request_type = payload["request"]["type"]
             if request_type == "canYouAnswer":
                 print("Got a canYouAnswer request:")
             
-            elif request_type == intent:
+            elif request_type == "intent":
                 print("Got an intent from registry: ")
-                request_id_id = 0
-                await self.__answer_request(payload[request_id_id])
+                await self.__answer_request(payload["requestId"])
 
             print(payload)
             
@@ -277,6 +303,7 @@ async def main():
     # Connect to the skill registry and send utterances and 
     await client.init()
     await client.registerIntents()
+    heartbeats = asyncio.create_task(client.heartbeats())
 
     # Perform notifications and queries, note this can be done whenever
     await client.notification()
@@ -287,6 +314,7 @@ async def main():
     await wait
 
     # If by wathever reason we have to shutdown call this
+    heartbeats.cancel()
     await client.close()
 
 
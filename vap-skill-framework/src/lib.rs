@@ -1,25 +1,61 @@
 mod load;
 
-use std::{io::Cursor, path::Path, time::Duration};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::{path::Path, sync::{mpsc as std_mpsc, Arc, Mutex}, thread, time::{Duration, Instant}};
 
 use coap::CoAPClient;
-use coap_lite::{MessageClass, RequestType as Method, ResponseType};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, ObserveOption, Packet, RequestType as Method, ResponseType};
 use fluent_langneg::negotiate_languages;
 use futures::channel::mpsc;
-use log::warn;
+use log::{debug, info, warn};
 use serde::Serialize;
 use thiserror::Error;
 use unic_langid::LanguageIdentifier;
+use vap_common_skill::encoding;
+use vap_common_skill::oscore::{self, SecurityContext};
+use vap_common_skill::version::{MIN_VAP_VERSION, VAP_VERSION};
 use vap_common_skill::structures::{msg_notification::Data, msg_query::QueryData, *, msg_skill_request::RequestSlot};
 
-pub use vap_common_skill::structures::{msg_skill_request::RequestDataKind, PlainCapability};
+pub use vap_common_skill::structures::{
+    msg_skill_request::{system_events, EventData, RequestDataKind},
+    PlainCapability,
+};
+pub use vap_common_skill::version::Version;
+
+/// How a skill connects to the registry, see `Skill::with_options`
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// The token of a previous connection, see `Skill::with_token`
+    pub token: Option<String>,
+    /// How long to wait for the user to accept us, `Error::ApprovalPending`
+    /// afterwards. With `Duration::ZERO` it is returned right away, connect
+    /// again later to know whether we were accepted.
+    pub approval_wait: Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            token: None,
+            approval_wait: DEFAULT_APPROVAL_WAIT,
+        }
+    }
+}
 
 /// The skill itself, use this to communicate with the registry.
 pub struct Skill {
     client: CoAPClient,
     id: String,
     langs: Vec<LanguageIdentifier>,
+    vap_version: Version,
+    token: Option<String>,
+    /// Protects our messages, only if the registry supports OSCORE
+    oscore: Option<Arc<Mutex<SecurityContext>>>,
     sender: mpsc::Sender<SkillRequest>,
+    /// Dropping it stops the heartbeats
+    _heartbeat: Option<std_mpsc::Sender<()>>,
 }
 
 impl Skill {
@@ -32,6 +68,9 @@ impl Skill {
     /// itself so that it receives requests. Returns both itself and a channel
     /// that you will use to receive events. This follows RAII and as as soon as
     /// it is dropped will disconnect from the skill registry.
+    /// If the registry asks the user whether to accept us, waits for them for
+    /// up to 10 minutes (`Error::ApprovalPending` afterwards), see
+    /// `with_options` to wait less.
     /// 
     /// # Arguments
     /// 
@@ -45,18 +84,50 @@ impl Skill {
         S2: Into<String>,
         P: AsRef<Path> + Clone,
     {
+        Self::with_token(name, id, intents, None)
+    }
+
+    /// Same as `new`, but proves that this is the same skill as a previous
+    /// connection by sending its token (see `token`). Needed when the registry
+    /// still regards this skill as connected (e.g: after a crash or a restart
+    /// of the registry). If the registry still has our intents (and they didn't
+    /// change since) they are not sent again.
+    pub fn with_token<S1, S2, P>(name: S1, id: S2, intents: P, token: Option<String>) -> Result<(Self, SkillIn)>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        P: AsRef<Path> + Clone,
+    {
+        Self::with_options(name, id, intents, ConnectOptions {token, ..Default::default()})
+    }
+
+    /// Same as `new`, with everything about connecting that can be chosen.
+    /// The thread is blocked while waiting for the user to accept us, a zero
+    /// `approval_wait` doesn't wait at all.
+    pub fn with_options<S1, S2, P>(name: S1, id: S2, intents: P, options: ConnectOptions) -> Result<(Self, SkillIn)>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        P: AsRef<Path> + Clone,
+    {
+        let ConnectOptions {token, approval_wait} = options;
         let id_str = id.into();
-        let payload = rmp_serde::to_vec_named(&MsgConnect {
+        let payload = encoding::to_vec(&MsgConnect {
             id: id_str.clone(),
             name: name.into(),
-            vap_version: "Alpha".into(),
+            vap_version: VAP_VERSION.to_string(),
+            min_vap_version: Some(MIN_VAP_VERSION.to_string()),
+            unique_authentication_token: token.clone(),
+            intents_digest: Some(load::digest(intents.clone())),
         })
         .expect("Failed to make initial payload, report this");
         let mut client = CoAPClient::new(Self::get_address())?;
 
         let mut remaining_retries = 3;
+        let mut last_error = Error::Unknown;
+        let waiting_since = Instant::now();
         while remaining_retries > 0 {
-            
+
             let resp = client.request_path(
                 "vap/skillRegistry/connect",
                 Method::Post,
@@ -67,33 +138,90 @@ impl Skill {
             match resp.message.header.code {
                 MessageClass::Response(ResponseType::Created) => {
                     let payload: MsgConnectResponse =
-                        rmp_serde::from_read(Cursor::new(resp.message.payload)).unwrap();
+                        encoding::from_slice(&resp.message.payload).unwrap();
                     let (sender, receiver) = mpsc::channel(10);
 
+                    // Registries that don't negotiate only know about the version we sent
+                    let vap_version = payload.vap_version.unwrap_or(VAP_VERSION);
+                    // A token is only sent if ours was not valid or we had none
+                    let token = payload.unique_authentication_token.or_else(|| token.clone());
+                    // Registries that support OSCORE send an ID Context
+                    let oscore = token.as_deref()
+                        .zip(payload.oscore_id_context.as_deref())
+                        .map(|(token, id_context)| Arc::new(Mutex::new(oscore::skill_context(token, id_context))));
+                    let heartbeat = payload.heartbeat_secs
+                        .map(|secs| send_heartbeats(id_str.clone(), token.clone(), oscore.clone(), secs));
                     let mut skill = Self {
                         client,
                         id: id_str,
-                        langs: payload.langs.into_iter().map(|l| l.into()).collect(),
+                        vap_version,
+                        token,
+                        oscore,
+                        langs: payload
+                            .langs
+                            .into_iter()
+                            .filter_map(|l| match LanguageIdentifier::try_from(&l) {
+                                Ok(id) => Some(id),
+                                Err(e) => {
+                                    warn!("The registry sent an invalid language ({}), will be ignored: {}", l, e);
+                                    None
+                                }
+                            })
+                            .collect(),
                         sender,
+                        _heartbeat: heartbeat,
                     };
 
-                    skill.register_intents(intents)?;
+                    // The registry may still have them from a previous connection
+                    if !payload.intents_known {
+                        skill.register_intents(intents)?;
+                    }
                     skill.register()?;
 
                     return Ok((skill, receiver))
                 }
+                MessageClass::Response(ResponseType::ServiceUnavailable) => {
+                    // The user has to accept us first, this doesn't count as a retry
+                    let remaining = approval_wait.saturating_sub(waiting_since.elapsed());
+                    if remaining.is_zero() {
+                        return Err(Error::ApprovalPending);
+                    }
+                    let wait = Duration::from_secs(max_age(&resp.message).unwrap_or(DEFAULT_MAX_AGE).into());
+                    let wait = wait.min(remaining);
+                    info!("Waiting to be accepted, trying again in {} seconds", wait.as_secs());
+                    std::thread::sleep(wait);
+                    continue;
+                }
+                MessageClass::Response(ResponseType::TooManyRequests) => {
+                    let wait = max_age(&resp.message).unwrap_or(DEFAULT_MAX_AGE);
+                    return Err(Error::TooManyRequests(wait));
+                }
                 MessageClass::Response(ResponseType::BadRequest) => {
+                    match read_error(ResponseType::BadRequest, &resp.message.payload) {
+                        e @ Error::VersionIncompatible(_) => return Err(e),
+                        e => last_error = e,
+                    }
                     remaining_retries -=1;
-                    println!("There's seemingly some problem, waiting and retrying");
+                    warn!("The registry didn't accept our connect ({}), waiting and retrying", last_error);
                 }
-                _ => {
-                    println!("{}", resp.message.header.code);
-                    panic!("ERROR, got unexpected message!!")
+                code => {
+                    return Err(read_error(extract_type(code), &resp.message.payload));
                 }
             }
             std::thread::sleep(Duration::from_secs(1));
         }
-        panic!("Connection failed!");
+        Err(last_error)
+    }
+
+    /// The VAP version agreed with the registry
+    pub fn vap_version(&self) -> Version {
+        self.vap_version
+    }
+
+    /// The token given by the registry to this skill, store it to connect
+    /// again with `with_token` while the registry still knows about us.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
     }
 
     fn send_message<T: Serialize>(
@@ -103,30 +231,22 @@ impl Skill {
         data: T,
     ) -> Result<(ResponseType, Vec<u8>)> {
         println!("Sending message");
-        let d = rmp_serde::to_vec_named(&data).expect("Failed to encode message, report this");
-        let resp = self
-            .client
-            .request_path(path, method, Some(d), None)
-            .unwrap();
+        let d = encoding::to_vec(&data).expect("Failed to encode message, report this");
+        let request = new_request(method, path, Some(d), self.token.as_deref());
+        let resp = send(&self.client, self.oscore.as_deref(), &request)?;
         println!("Received!");
 
+        if let MessageClass::Response(ResponseType::TooManyRequests) = resp.message.header.code {
+            let wait = max_age(&resp.message).unwrap_or(DEFAULT_MAX_AGE);
+            return Err(Error::TooManyRequests(wait));
+        }
+
         Ok((
             extract_type(resp.message.header.code), 
             resp.message.payload
         ))
     }
 
-    fn send_message_no_payload(&mut self, method: Method, path: &str) -> ResponseType {
-        extract_type(
-            self.client
-                .request_path(path, method, None, None)
-                .unwrap()
-                .message
-                .header
-                .code,
-        )
-    }
-
     pub fn register_intents<P>(&mut self, intents: P) -> Result<()>
     where
         P: AsRef<Path> + Clone,
@@ -152,19 +272,20 @@ impl Skill {
             },
         )? {
             (ResponseType::Created, _) => Ok(()),
-            _ => Err(Error::Unknown),
+            (code, d) => Err(read_error(code, &d)),
         }
     }
 
     fn close(&mut self) -> Result<()> {
-        if self.send_message_no_payload(
+        match self.send_message(
             Method::Delete,
             &format!("vap/skillRegistry/skills/{}", &self.id),
-        ) != ResponseType::Deleted
-        {
-            Err(Error::Unknown)
-        } else {
-            Ok(())
+            MsgSkillClose {
+                skill_id: self.id.clone(),
+            },
+        )? {
+            (ResponseType::Deleted, _) => Ok(()),
+            (code, d) => Err(read_error(code, &d)),
         }
     }
 
@@ -191,9 +312,9 @@ impl Skill {
                 data,
             },
         )? {
-            (ResponseType::Content, d) => Ok(rmp_serde::from_read(Cursor::new(d))
+            (ResponseType::Content, d) => Ok(encoding::from_slice(&d)
                 .expect("Failed to create MsgNotification, report this")),
-            _ => Err(Error::Unknown),
+            (code, d) => Err(read_error(code, &d)),
         }
     }
 
@@ -207,39 +328,95 @@ impl Skill {
                 data,
             },
         )? {
-            (ResponseType::Content, d) => Ok(rmp_serde::from_read(Cursor::new(d))
+            (ResponseType::Content, d) => Ok(encoding::from_slice(&d)
                 .expect("Failed to create MsgQuery, report this")),
-            (ResponseType::BadRequest, _) => Err(Error::BadRequest),
-
-            _ => Err(Error::Unknown),
+            (code, d) => Err(read_error(code, &d)),
         }
     }
 
+    /// Observes our requests, on a client of its own so that notifications
+    /// don't get mixed with the answers to our messages
     fn register(&mut self) -> Result<()> {
+        let client = CoAPClient::new(Self::get_address())?;
+        let mut request = new_request(
+            Method::Get,
+            &format!("vap/skillRegistry/skills/{}", &self.id),
+            None,
+            self.observation_token().as_deref(),
+        );
+        request.set_observe_flag(ObserveOption::Register);
+        let resp = exchange(&client, &request)?;
+        if resp.message.header.code != MessageClass::Response(ResponseType::Content) {
+            return Err(read_error(extract_type(resp.message.header.code), &resp.message.payload));
+        }
+
         let mut sender = self.sender.clone();
-        self.client
-            .observe(
-                &format!("vap/skillRegistry/skills/{}", &self.id),
-                move |m| {
-                    println!("Oberseve returned something!!!");
-                    println!("{:?}", m);
-                    if !m.payload.is_empty()
-                        && m.header.code == MessageClass::Response(ResponseType::Content)
-                    {
-                        println!("Msg:  {:?}", debug_msg_pack(&m.payload));
-
-                        match rmp_serde::from_read::<_, MsgSkillRequest>(Cursor::new(m.payload)) {
-                            Ok(payload) => {
-                                sender.try_send(payload.into()).unwrap();
-                            }
-                            Err(e) => {
-                                warn!("Received a bad msgpack message, will be ignored: {}", e);
-                            }
+        thread::spawn(move || loop {
+            let m = match client.receive() {
+                Ok(resp) => resp.message,
+                // Nothing arrived in a while, keep waiting unless we are gone
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if sender.is_closed() {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Stopped receiving requests: {}", e);
+                    break;
+                }
+            };
+
+            debug!("Notification received: {:?}", m);
+            let mut answer = MessageType::Acknowledgement;
+            if !m.payload.is_empty()
+                && m.header.code == MessageClass::Response(ResponseType::Content)
+            {
+                debug!("Request: {}", debug_msg_pack(&m.payload));
+
+                match encoding::from_slice::<MsgSkillRequest>(&m.payload) {
+                    Ok(payload) => match sender.try_send(payload.into()) {
+                        Ok(()) => {}
+                        // Not acknowledged, the registry will send it again
+                        Err(e) if e.is_full() => {
+                            warn!("Too many requests waiting, one will be received later");
+                            continue;
                         }
+                        // Nobody takes our requests anymore
+                        Err(_) => answer = MessageType::Reset,
+                    },
+                    Err(e) => {
+                        warn!("Received a bad msgpack message, will be ignored: {}", e);
                     }
-                },
-            )?;
-            Ok(())
+                }
+            }
+
+            if m.header.get_type() == MessageType::Confirmable {
+                let mut ack = CoapRequest::new();
+                ack.message.header.set_type(answer);
+                ack.message.header.code = MessageClass::Empty;
+                ack.message.header.message_id = m.header.message_id;
+                if let Err(e) = client.send(&ack) {
+                    warn!("Couldn't acknowledge a request: {}", e);
+                }
+            }
+            if answer == MessageType::Reset {
+                break;
+            }
+        });
+        Ok(())
+    }
+
+    /// What we observe with, observation can't be protected with OSCORE and
+    /// then our token must not travel in the clear
+    fn observation_token(&self) -> Option<String> {
+        let token = self.token.as_deref()?;
+        let id_context = self.oscore.as_ref()
+            .and_then(|c| c.lock().unwrap().id_context().map(|c| String::from_utf8_lossy(c).into_owned()));
+        match id_context {
+            Some(id_context) => Some(oscore::observation_token(token, &id_context)),
+            None => Some(token.to_string()),
+        }
     }
 
     /// Answer an incoming request
@@ -259,13 +436,129 @@ impl Skill {
 
 impl Drop for Skill {
     fn drop(&mut self) {
-        self.close().unwrap();
+        // The registry will regard us as gone anyway once we stop sending heartbeats
+        if let Err(e) = self.close() {
+            warn!("Couldn't disconnect from the registry: {}", e);
+        }
     }
 }
 
+/// Lets the registry know we are alive every `secs` seconds, until the
+/// returned sender is dropped
+fn send_heartbeats(id: String, token: Option<String>, oscore: Option<Arc<Mutex<SecurityContext>>>, secs: u32) -> std_mpsc::Sender<()> {
+    let (stop, stopped) = std_mpsc::channel();
+    thread::spawn(move || {
+        let client = match CoAPClient::new(Skill::get_address()) {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Couldn't start sending heartbeats, the registry will regard us as gone: {}", e);
+                return;
+            }
+        };
+        let payload = encoding::to_vec(&MsgHeartbeat { skill_id: id })
+            .expect("Failed to encode heartbeat, report this");
+
+        while let Err(std_mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_secs(secs.into())) {
+            let request = new_request(Method::Post, "vap/skillRegistry/heartbeat", Some(payload.clone()), token.as_deref());
+            match send(&client, oscore.as_deref(), &request) {
+                Ok(resp) if resp.message.header.code != MessageClass::Response(ResponseType::Changed) => {
+                    warn!("The registry didn't accept a heartbeat ({}), it may regard us as gone", resp.message.header.code);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Couldn't send a heartbeat: {}", e),
+            }
+        }
+    });
+
+    stop
+}
+
+/// A confirmable request to the registry. Every message after connect carries
+/// our token, that's how the registry knows it's us.
+fn new_request(method: Method, path: &str, payload: Option<Vec<u8>>, token: Option<&str>) -> CoapRequest<SocketAddr> {
+    static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut request = CoapRequest::new();
+    request.set_method(method);
+    request.set_path(path);
+    request.message.header.message_id = id;
+    request.message.set_token(id.to_be_bytes().to_vec());
+    request.message.payload = payload.unwrap_or_default();
+    if let Some(token) = token {
+        request.message.add_option(CoapOption::Unknown(AUTH_TOKEN_OPTION), token.as_bytes().to_vec());
+    }
+    request
+}
+
+/// Sends a request and waits for its answer
+fn exchange(client: &CoAPClient, request: &CoapRequest<SocketAddr>) -> io::Result<CoapResponse> {
+    client.send(request)?;
+    loop {
+        let resp = client.receive()?;
+        // Answers to requests that we gave up on can still arrive
+        if resp.message.get_token() == request.message.get_token() {
+            return Ok(resp);
+        }
+    }
+}
+
+/// Sends a request and waits for its answer, protected if we use OSCORE
+fn send(client: &CoAPClient, oscore: Option<&Mutex<SecurityContext>>, request: &CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+    match oscore {
+        Some(context) => exchange_protected(client, context, request),
+        None => Ok(exchange(client, request)?),
+    }
+}
+
+/// Like `exchange`, but the request is protected with OSCORE, and so must be
+/// its answer unless it is an error (those can't always be protected)
+fn exchange_protected(
+    client: &CoAPClient,
+    context: &Mutex<SecurityContext>,
+    request: &CoapRequest<SocketAddr>,
+) -> Result<CoapResponse> {
+    let bytes = request.message.to_bytes().map_err(|_| Error::Oscore(oscore::Error::Malformed))?;
+    let (protected, request_ref) = context.lock().unwrap().protect_request(&bytes).map_err(Error::Oscore)?;
+    let mut protected_request = CoapRequest::new();
+    protected_request.message = Packet::from_bytes(&protected).map_err(|_| Error::Oscore(oscore::Error::Malformed))?;
+
+    let mut resp = exchange(client, &protected_request)?;
+    let bytes = resp.message.to_bytes().map_err(|_| Error::Oscore(oscore::Error::Malformed))?;
+    let unprotected = context.lock().unwrap().unprotect_response(&bytes, &request_ref);
+    match unprotected {
+        Ok(plain) => {
+            resp.message = Packet::from_bytes(&plain).map_err(|_| Error::Oscore(oscore::Error::Malformed))?;
+            Ok(resp)
+        }
+        Err(oscore::Error::NotProtected) if !is_success(resp.message.header.code) => Ok(resp),
+        Err(e) => Err(Error::Oscore(e)),
+    }
+}
+
+fn is_success(code: MessageClass) -> bool {
+    let raw: u8 = code.into();
+    raw >> 5 == 2
+}
+
 fn debug_msg_pack(payload: &[u8]) -> String {
-    let v: Value = rmp_serde::from_read(Cursor::new(payload.to_vec())).unwrap();
-    v.to_string()
+    match encoding::from_slice::<Value>(payload) {
+        Ok(v) => v.to_string(),
+        Err(e) => format!("(not msgpack: {})", e),
+    }
+}
+
+/// Seconds to wait when a Max-Age is needed but was not sent (as in CoAP)
+const DEFAULT_MAX_AGE: u32 = 60;
+/// How long to wait for the user to accept us before giving up, unless told otherwise
+const DEFAULT_APPROVAL_WAIT: Duration = Duration::from_secs(10 * 60);
+
+/// How many seconds the registry told us to wait before trying again
+fn max_age(packet: &Packet) -> Option<u32> {
+    packet
+        .get_option(CoapOption::MaxAge)
+        .and_then(|values| values.front())
+        .map(|bytes| bytes.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b)))
 }
 
 fn extract_type(code: MessageClass) -> ResponseType {
@@ -286,23 +579,41 @@ pub struct SkillRequest {
 #[derive(Clone, Debug)]
 pub enum Request {
     Intent(String, RequestData),
-    Event(String, RequestData),
-    CanAnswer(String, RequestData)
+    Event(EventData, RequestData),
+    CanAnswer(String, RequestData),
+    Standalone(RequestData)
 }
 
 #[derive(Clone, Debug)]
 pub enum RequestStr<'a> {
     Intent(&'a str, &'a RequestData),
     Event(&'a str, &'a RequestData),
-    CanAnswer(&'a str, &'a RequestData)
+    CanAnswer(&'a str, &'a RequestData),
+    Standalone(&'a RequestData)
 }
 
 impl Request {
     pub fn as_str(& self) -> RequestStr<'_>  {
         match &self {
             Request::Intent(s, d) => RequestStr::Intent(s.as_str(), d),
-            Request::Event(s, d) => RequestStr::Event(s.as_str(), d),
-            Request::CanAnswer(s, d) => RequestStr::CanAnswer(s.as_str(), d)
+            Request::Event(e, d) => RequestStr::Event(e.name.as_str(), d),
+            Request::CanAnswer(s, d) => RequestStr::CanAnswer(s.as_str(), d),
+            Request::Standalone(d) => RequestStr::Standalone(d)
+        }
+    }
+
+    /// The payload of the event, if this is one
+    pub fn event_payload(&self) -> Option<&AssociativeMap> {
+        match self {
+            Request::Event(e, _) => Some(&e.payload),
+            _ => None
+        }
+    }
+
+    /// The data of the request, whatever the kind
+    pub fn data(&self) -> &RequestData {
+        match self {
+            Request::Intent(_, d) | Request::Event(_, d) | Request::CanAnswer(_, d) | Request::Standalone(d) => d
         }
     }
 }
@@ -310,20 +621,31 @@ impl Request {
 #[derive(Clone, Debug)]
 pub struct RequestData {
     pub locale: String,
-    pub slots: Vec<RequestSlot>
+    /// What the user said, if the request comes from an utterance
+    pub input: Option<String>,
+    pub slots: Vec<RequestSlot>,
+    /// Data that came along with the request (e.g: audio or images)
+    pub capabilities: Vec<PlainCapability>
 }
 
 impl From<MsgSkillRequest> for SkillRequest {
     fn from(msg: MsgSkillRequest) -> Self {
         let req_data = RequestData {
             locale: msg.request.locale,
-            slots: msg.request.slots
+            input: msg.request.input,
+            slots: msg.request.slots,
+            capabilities: msg.request.capabilities
         };
 
         let request = match msg.request.type_ {
             RequestDataKind::CanAnswer => Request::CanAnswer(msg.request.intent, req_data),
-            RequestDataKind::Event => Request::Event(msg.request.intent, req_data),
-            RequestDataKind::Intent => Request::Intent(msg.request.intent, req_data)
+            RequestDataKind::Event => {
+                // Older registries sent the name of the event as the intent
+                let event = msg.request.event.unwrap_or_else(|| EventData::new(msg.request.intent, SYSTEM_SELF_ID));
+                Request::Event(event, req_data)
+            }
+            RequestDataKind::Intent => Request::Intent(msg.request.intent, req_data),
+            RequestDataKind::Standalone => Request::Standalone(req_data)
         };
 
         Self { 
@@ -345,10 +667,79 @@ pub enum Error {
     #[error("The data sent had a wrong format or didn't meet the VAP rules")]
     BadRequest,
 
+    #[error("The registry could not find {0}")]
+    NotFound(String),
+
+    #[error("The registry could not read our message")]
+    MalformedContent,
+
+    #[error("The message lacked the field {0}")]
+    MissingField(String),
+
+    #[error("The registry does not support our VAP version, it supports: {0}")]
+    VersionIncompatible(String),
+
+    #[error("The connection was denied by policy or by the user")]
+    ConnectionDenied,
+
+    #[error("The user didn't decide whether to accept us in time")]
+    ApprovalPending,
+
+    #[error("We are not allowed to do that")]
+    Unauthorized,
+
+    #[error("We sent too much, the registry asks to wait {0} seconds")]
+    TooManyRequests(u32),
+
+    #[error("The registry does not know the skill {0}")]
+    WrongSkillId(String),
+
+    #[error("The NLU data references the unknown entity {0}")]
+    MissingEntity(String),
+
+    #[error("The NLU data references the unknown slot {0}")]
+    MissingSlot(String),
+
+    #[error("OSCORE: {0}")]
+    Oscore(oscore::Error),
+
+    #[error("The registry answered with an error: {0}")]
+    Other(VapError),
+
     #[error("We got an error, but we don't know why")]
     Unknown,
 }
 
+impl From<VapError> for Error {
+    fn from(e: VapError) -> Self {
+        let object = e.object.clone().unwrap_or_default();
+        match e.type_.as_str() {
+            vap_error::NOT_FOUND => Error::NotFound(object),
+            vap_error::MALFORMED_CONTENT => Error::MalformedContent,
+            vap_error::MISSING_FIELD => Error::MissingField(object),
+            vap_error::VERSION_INCOMPATIBLE => Error::VersionIncompatible(object),
+            vap_error::CONNECTION_DENIED => Error::ConnectionDenied,
+            vap_error::UNAUTHORIZED => Error::Unauthorized,
+            vap_error::WRONG_SKILL_ID => Error::WrongSkillId(object),
+            vap_error::MISSING_ENTITY => Error::MissingEntity(object),
+            vap_error::MISSING_SLOT => Error::MissingSlot(object),
+            _ => Error::Other(e),
+        }
+    }
+}
+
+/// Turns an error answer from the registry into an [`Error`], if the payload
+/// isn't a VAP error we can only guess from the code.
+fn read_error(code: ResponseType, payload: &[u8]) -> Error {
+    match encoding::from_slice::<VapError>(payload) {
+        Ok(e) => e.into(),
+        Err(_) => match code {
+            ResponseType::BadRequest => Error::BadRequest,
+            _ => Error::Unknown,
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::Skill;
@@ -28,6 +28,32 @@ where
         .collect()
 }
 
+/// Identifies the contents of the intents folder, changes with any of them
+pub fn digest<P>(intents: P) -> String
+where
+    P: AsRef<Path>,
+{
+    let mut files: Vec<_> = intents
+        .as_ref()
+        .read_dir()
+        .unwrap()
+        .map(|r| r.unwrap().path())
+        .filter(|p| p.is_file())
+        .collect();
+    files.sort();
+
+    // FNV-1a, the names are hashed too so that moving intents to another language counts
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for path in files {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        for b in name.bytes().chain([0]).chain(fs::read(&path).unwrap()).chain([0]) {
+            hash = (hash ^ u64::from(b)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    format!("{:016x}", hash)
+}
+
 pub fn load_intents<P>(langs: &[&LanguageIdentifier], intents: P) -> Vec<NluData>
 where
     P: AsRef<Path>,
@@ -103,7 +129,57 @@ struct IntentData {
     utterances: Vec<String>,
 
     #[serde(default)]
-    slots: Option<HashMap<String, String>>,
+    slots: Option<HashMap<String, SlotData>>,
+}
+
+/// A slot can be written just as the name of it's entity or as a table:
+/// ```toml
+/// [intents.main.set_alarm.slots]
+/// time = { entity = "time", required = true, prompt = "When?", reprompt = "At what time?" }
+/// name = "alarm_name"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum SlotData {
+    Entity(String),
+    Full {
+        entity: String,
+
+        #[serde(default)]
+        required: bool,
+
+        #[serde(default)]
+        prompt: Option<String>,
+
+        #[serde(default)]
+        reprompt: Option<String>,
+    },
+}
+
+impl SlotData {
+    fn into_vap(self, name: String) -> NluDataSlot {
+        match self {
+            SlotData::Entity(entity) => NluDataSlot {
+                name,
+                entity,
+                required: false,
+                prompt: None,
+                reprompt: None,
+            },
+            SlotData::Full {
+                entity,
+                required,
+                prompt,
+                reprompt,
+            } => NluDataSlot {
+                name,
+                entity,
+                required,
+                prompt,
+                reprompt,
+            },
+        }
+    }
 }
 
 impl IntentData {
@@ -118,7 +194,7 @@ impl IntentData {
             .slots
             .unwrap_or_default()
             .into_iter()
-            .map(|(n, e)| NluDataSlot { name: n, entity: e })
+            .map(|(n, s)| s.into_vap(n))
             .collect();
 
         NluDataIntent {
@@ -131,6 +207,9 @@ impl IntentData {
 
 #[derive(Debug, Deserialize)]
 struct EntityData {
+    #[serde(default)]
+    strict: bool,
+
     data: Vec<NluDataEntityData>,
 }
 
@@ -138,7 +217,7 @@ impl EntityData {
     pub fn into_vap(self, name: String) -> NluDataEntity {
         NluDataEntity {
             name,
-            strict: false,
+            strict: self.strict,
             data: self.data,
         }
     }
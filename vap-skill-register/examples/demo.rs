@@ -2,12 +2,13 @@ use std::{collections::HashMap, time::Duration};
 
 use tokio::sync::oneshot;
 use vap_skill_register::{
+    encoding,
     structures::{
         msg_query_response::{QueryData, QueryDataCapability},
         msg_skill_request::{ClientData, RequestData, RequestDataKind},
         Language, MsgConnectResponse, MsgQueryResponse, MsgSkillRequest, Value,
     },
-    Response, ResponseType, SkillRegister, SkillRegisterMessage, SkillRegisterOut,
+    Approval, Response, ResponseType, SkillRegister, SkillRegisterMessage, SkillRegisterOut,
     SkillRegisterStream, RequestResponse,
 };
 
@@ -27,15 +28,38 @@ impl MyData {
         loop {
             let (msg, responder) = stream.recv().await?;
             let resp = match msg {
-                SkillRegisterMessage::Connect(m) => {
-                    println!("{} wants to connect", m.id);
+                SkillRegisterMessage::Approval(a) => {
+                    // Only sent if the registry requires approvals, we accept everyone
+                    println!("{} ({}) wants to be accepted", a.name, a.id);
+                    a.answer(Approval::AllowAlways)?;
+                    continue;
+                }
+                SkillRegisterMessage::Restored(s) => {
+                    // Only sent if the registry has a storage
+                    println!("{} was connected before the restart", s.id);
+                    continue;
+                }
+                SkillRegisterMessage::Disconnected(id) => {
+                    // Only sent if the registry requires heartbeats
+                    println!("{} is gone", id);
+                    continue;
+                }
+                SkillRegisterMessage::Connect(m, version) => {
+                    println!("{} wants to connect using VAP {}", m.id, version);
                     if let Some(c) = self.name.take() { c.send(m.id).unwrap() }
-                    let data = rmp_serde::to_vec(&MsgConnectResponse {
+                    let data = encoding::to_vec(&MsgConnectResponse {
                         langs: vec![Language {
                             language: "en".to_string(),
                             country: Some("US".to_string()),
-                            extra: None,
+                            script: None,
+                            variants: vec![],
                         }],
+                        // Filled by the registry
+                        vap_version: None,
+                        unique_authentication_token: None,
+                        oscore_id_context: None,
+                        intents_known: false,
+                        heartbeat_secs: None,
                     })
                     .unwrap();
                     
@@ -96,7 +120,7 @@ impl MyData {
                             }
                         })
                         .collect::<Vec<_>>();
-                    let payload = rmp_serde::to_vec(&MsgQueryResponse { data }).unwrap();
+                    let payload = encoding::to_vec(&MsgQueryResponse { data }).unwrap();
 
                     Response {
                         status: ResponseType::Content,
@@ -148,9 +172,13 @@ impl MyDataOut {
                         type_: RequestDataKind::Intent,
                         intent: "hello".into(),
                         locale: "en-US".into(),
+                        input: Some("hello there".into()),
+                        event: None,
                         slots: vec![],
+                        capabilities: vec![],
                     },
                 },
+                Duration::from_secs(10),
             )
             .await
             .unwrap();
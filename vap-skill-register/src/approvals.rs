@@ -0,0 +1,212 @@
+//! Whether the user accepted a skill. Skills that are not known yet have to
+//! wait until the user decides, decisions that are permanent are kept in a
+//! TOML file so that those skills can reconnect without asking again.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+pub(crate) type SharedApprovals = Arc<SyncMutex<Approvals>>;
+
+/// The answer of the user to a skill wanting to connect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Approval {
+    /// Accept this connection only
+    AllowOnce,
+    /// Accept this and any future connection
+    AllowAlways,
+    /// Deny this connection only, the user will be asked again next time
+    DenyOnce,
+    /// Deny this and any future connection
+    DenyAlways,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Decision {
+    Allowed,
+    Denied,
+}
+
+/// What to do with a connecting skill
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Status {
+    Allowed,
+    Denied,
+    /// The user has been asked already, but has not answered yet
+    Pending,
+    /// The user has to be asked
+    Unknown,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ApprovalsFile {
+    #[serde(default)]
+    skills: HashMap<String, Decision>,
+}
+
+/// The decisions of the user about skills
+#[derive(Debug, Default)]
+pub struct Approvals {
+    path: Option<PathBuf>,
+    permanent: HashMap<String, Decision>,
+    once: HashMap<String, Decision>,
+    pending: HashSet<String>,
+}
+
+impl Approvals {
+    /// Decisions are only kept while the registry is running
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Decisions are read from and saved into this file, it is created if it
+    /// does not exist
+    pub fn from_file<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let path = path.into();
+        let file: ApprovalsFile = match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => ApprovalsFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Approvals {
+            path: Some(path),
+            permanent: file.skills,
+            ..Default::default()
+        })
+    }
+
+    /// Where a skill stands, decisions for one connection are used up here.
+    /// Unknown skills are regarded as pending from now on.
+    pub(crate) fn status(&mut self, skill_id: &str) -> Status {
+        if let Some(decision) = self
+            .once
+            .remove(skill_id)
+            .or_else(|| self.permanent.get(skill_id).copied())
+        {
+            match decision {
+                Decision::Allowed => Status::Allowed,
+                Decision::Denied => Status::Denied,
+            }
+        } else if !self.pending.insert(skill_id.to_string()) {
+            Status::Pending
+        } else {
+            Status::Unknown
+        }
+    }
+
+    fn decide(&mut self, skill_id: String, approval: Approval) -> Result<(), Error> {
+        self.pending.remove(&skill_id);
+        match approval {
+            Approval::AllowOnce => {
+                self.once.insert(skill_id, Decision::Allowed);
+            }
+            Approval::DenyOnce => {
+                self.once.insert(skill_id, Decision::Denied);
+            }
+            Approval::AllowAlways => {
+                self.permanent.insert(skill_id, Decision::Allowed);
+                self.save()?;
+            }
+            Approval::DenyAlways => {
+                self.permanent.insert(skill_id, Decision::Denied);
+                self.save()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let file = ApprovalsFile {
+                skills: self.permanent.clone(),
+            };
+            fs::write(path, toml::to_string(&file)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A skill that is not known wants to connect, the user has to decide
+/// whether to accept it. Meanwhile the skill is told to try again later.
+/// Dropping it without an answer means the user will be asked again the
+/// next time the skill tries.
+#[derive(Debug)]
+pub struct ApprovalRequest {
+    /// The id of the skill
+    pub id: String,
+    /// The human readable name of the skill
+    pub name: String,
+    approvals: SharedApprovals,
+    answered: bool,
+}
+
+impl ApprovalRequest {
+    pub(crate) fn new(id: String, name: String, approvals: SharedApprovals) -> Self {
+        ApprovalRequest {
+            id,
+            name,
+            approvals,
+            answered: false,
+        }
+    }
+
+    /// Records what the user decided, it is applied the next time the skill
+    /// tries to connect
+    pub fn answer(mut self, approval: Approval) -> Result<(), Error> {
+        self.answered = true;
+        self.approvals.lock().unwrap().decide(self.id.clone(), approval)
+    }
+}
+
+impl Drop for ApprovalRequest {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.approvals.lock().unwrap().pending.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_once_and_remembers_permanent_decisions() {
+        let mut approvals = Approvals::in_memory();
+        assert_eq!(approvals.status("com.example.test"), Status::Unknown);
+        assert_eq!(approvals.status("com.example.test"), Status::Pending);
+
+        approvals.decide("com.example.test".into(), Approval::AllowOnce).unwrap();
+        assert_eq!(approvals.status("com.example.test"), Status::Allowed);
+        assert_eq!(approvals.status("com.example.test"), Status::Unknown);
+
+        approvals.decide("com.example.test".into(), Approval::DenyAlways).unwrap();
+        assert_eq!(approvals.status("com.example.test"), Status::Denied);
+        assert_eq!(approvals.status("com.example.test"), Status::Denied);
+    }
+
+    #[test]
+    fn unanswered_requests_ask_again() {
+        let approvals = Arc::new(SyncMutex::new(Approvals::in_memory()));
+        assert_eq!(approvals.lock().unwrap().status("com.example.test"), Status::Unknown);
+
+        let request = ApprovalRequest::new("com.example.test".into(), "Test".into(), approvals.clone());
+        assert_eq!(approvals.lock().unwrap().status("com.example.test"), Status::Pending);
+        drop(request);
+        assert_eq!(approvals.lock().unwrap().status("com.example.test"), Status::Unknown);
+
+        let request = ApprovalRequest::new("com.example.test".into(), "Test".into(), approvals.clone());
+        request.answer(Approval::AllowOnce).unwrap();
+        assert_eq!(approvals.lock().unwrap().status("com.example.test"), Status::Allowed);
+    }
+}
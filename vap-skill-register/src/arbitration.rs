@@ -0,0 +1,188 @@
+//! Picks the skill that should answer a request, out of how confident each
+//! skill is in being able to answer it (see `SkillRegisterOut::skills_answerable`).
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+/// How skills are ranked, each skill scores its confidence plus its priority
+/// plus a bonus if it was used recently
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ArbitrationConfig {
+    /// Skills less confident than this are not regarded as able to answer
+    pub min_confidence: f32,
+    /// Skills whose scores are closer than this to the best one are regarded as
+    /// just as good, the user has to choose among them
+    pub ambiguity_margin: f32,
+    /// Added to the score of each skill (may be negative), by skill id
+    pub priorities: HashMap<String, f32>,
+    /// Added to the score of the skill used right now, it decreases until
+    /// nothing is added after `recency_secs`
+    pub recency_bonus: f32,
+    pub recency_secs: u32,
+}
+
+impl Default for ArbitrationConfig {
+    fn default() -> Self {
+        ArbitrationConfig {
+            min_confidence: 0.5,
+            ambiguity_margin: 0.1,
+            priorities: HashMap::new(),
+            recency_bonus: 0.1,
+            recency_secs: 300,
+        }
+    }
+}
+
+/// Which skill should answer
+#[derive(Clone, Debug, PartialEq)]
+pub enum Choice {
+    /// This skill is the best one
+    Skill(String),
+    /// No skill is confident enough
+    NoSkill,
+    /// These skills (best first) are just as good, ask the user with `prompt`
+    Ambiguous { skills: Vec<String>, prompt: String },
+}
+
+#[derive(Debug)]
+pub(crate) struct Arbiter {
+    config: ArbitrationConfig,
+    last_used: HashMap<String, Instant>,
+}
+
+impl Arbiter {
+    pub(crate) fn new(config: ArbitrationConfig) -> Self {
+        Arbiter {
+            config,
+            last_used: HashMap::new(),
+        }
+    }
+
+    /// Records that a skill was used, for the recency bonus
+    pub(crate) fn used(&mut self, skill_id: &str) {
+        self.last_used.insert(skill_id.to_string(), Instant::now());
+    }
+
+    /// Chooses among the confidences of several skills, `name` gives the human
+    /// readable name of a skill for the prompt
+    pub(crate) fn choose<F: Fn(&str) -> String>(
+        &self,
+        confidences: &HashMap<String, f32>,
+        name: F,
+    ) -> Choice {
+        self.choose_at(confidences, name, Instant::now())
+    }
+
+    fn choose_at<F: Fn(&str) -> String>(
+        &self,
+        confidences: &HashMap<String, f32>,
+        name: F,
+        now: Instant,
+    ) -> Choice {
+        let mut ranked: Vec<(&String, f32)> = confidences
+            .iter()
+            .filter(|(_, c)| **c >= self.config.min_confidence)
+            .map(|(id, c)| (id, c + self.bonus(id, now)))
+            .collect();
+        // Ties are broken by id, so that the same input always gives the same choice
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+        let best = match ranked.first() {
+            Some((_, score)) => *score,
+            None => return Choice::NoSkill,
+        };
+        let skills: Vec<String> = ranked
+            .into_iter()
+            .take_while(|(_, score)| best - score < self.config.ambiguity_margin)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        if skills.len() == 1 {
+            Choice::Skill(skills.into_iter().next().unwrap())
+        } else {
+            let names: Vec<String> = skills.iter().map(|id| name(id)).collect();
+            Choice::Ambiguous {
+                prompt: prompt(&names),
+                skills,
+            }
+        }
+    }
+
+    fn bonus(&self, skill_id: &str, now: Instant) -> f32 {
+        let priority = self.config.priorities.get(skill_id).copied().unwrap_or(0.0);
+        let recency = match self.last_used.get(skill_id) {
+            Some(used) if self.config.recency_secs > 0 => {
+                let elapsed = now.saturating_duration_since(*used).as_secs_f32();
+                let left = 1.0 - elapsed / self.config.recency_secs as f32;
+                self.config.recency_bonus * left.max(0.0)
+            }
+            _ => 0.0,
+        };
+
+        priority + recency
+    }
+}
+
+/// Asks the user to choose among skills (e.g: "Which one do you mean: Radio,
+/// Music or Podcasts?"), hosts in other languages should make their own.
+fn prompt(names: &[String]) -> String {
+    match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => {
+            format!("Which one do you mean: {} or {}?", rest.join(", "), last)
+        }
+        _ => format!("Do you mean {}?", names.concat()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn confidences(values: &[(&str, f32)]) -> HashMap<String, f32> {
+        values.iter().map(|(id, c)| (id.to_string(), *c)).collect()
+    }
+
+    fn name(id: &str) -> String {
+        id.trim_start_matches("com.example.").to_string()
+    }
+
+    #[test]
+    fn chooses_ranks_and_disambiguates() {
+        let mut config = ArbitrationConfig {
+            recency_bonus: 0.2,
+            ..Default::default()
+        };
+        config.priorities.insert("com.example.podcasts".into(), -0.3);
+        let mut arbiter = Arbiter::new(config);
+        let now = Instant::now();
+
+        let none = confidences(&[("com.example.radio", 0.2)]);
+        assert_eq!(arbiter.choose_at(&none, name, now), Choice::NoSkill);
+
+        let clear = confidences(&[("com.example.radio", 0.9), ("com.example.music", 0.6)]);
+        assert_eq!(arbiter.choose_at(&clear, name, now), Choice::Skill("com.example.radio".into()));
+
+        let close = confidences(&[
+            ("com.example.radio", 0.8),
+            ("com.example.music", 0.75),
+            ("com.example.podcasts", 0.8),
+        ]);
+        assert_eq!(
+            arbiter.choose_at(&close, name, now),
+            Choice::Ambiguous {
+                skills: vec!["com.example.radio".into(), "com.example.music".into()],
+                prompt: "Which one do you mean: radio or music?".into(),
+            }
+        );
+
+        // Having just used music makes it the best, but not after a while
+        arbiter.last_used.insert("com.example.music".into(), now);
+        assert_eq!(arbiter.choose_at(&close, name, now), Choice::Skill("com.example.music".into()));
+        let later = now + Duration::from_secs(300);
+        assert!(matches!(arbiter.choose_at(&close, name, later), Choice::Ambiguous { .. }));
+    }
+}
@@ -1,37 +1,162 @@
 //! The reference implementation of the VAP skill register.
 
+mod approvals;
+mod arbitration;
+mod limits;
 mod method_handlers;
+mod nlu;
+mod permissions;
+mod protection;
+mod server;
+mod storage;
+#[cfg(test)]
+mod test_utils;
 mod vars;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::io;
 use std::net::SocketAddr;
-use std::sync::{Arc, Barrier, Mutex as SyncMutex};
-use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, Instant};
 
-use coap::{CoAPClient, Server};
-use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, RequestType as Method};
 use futures::{
     channel::{mpsc, oneshot},
     lock::Mutex,
+    future::{join, join3, join_all},
+    stream::FuturesUnordered,
     SinkExt, StreamExt,
 };
+use log::warn;
 use thiserror::Error;
-use tokio::runtime::Runtime;
 use vap_common_skill::structures::msg_skill_request::{ClientData, RequestData};
 use vap_common_skill::structures::*;
 
 pub use coap_lite::ResponseType;
-pub use vap_common_skill::structures;
-pub use vars::{SYSTEM_SELF_ID, VAP_VERSION};
+pub use vap_common_skill::{encoding, structures};
+pub use vap_common_skill::version::{Version, VersionRange};
+pub use approvals::{Approval, ApprovalRequest, Approvals};
+pub use arbitration::{ArbitrationConfig, Choice};
+pub use limits::{LimitStats, Limits, Rate, SkillLimits};
+pub use nlu::Understood;
+pub use permissions::{Policy, Rule, SkillPolicy};
+pub use storage::{FileStorage, Storage};
+pub use vars::{APPROVAL_RETRY_SECS, MISSED_HEARTBEATS, RESTORE_GRACE_SECS, SUPPORTED_VAP_VERSIONS, SYSTEM_SELF_ID, VAP_VERSION};
+
+use approvals::SharedApprovals;
+use arbitration::Arbiter;
+use limits::{Limiter, SharedLimiter};
+use nlu::{SharedNlu, TemplateNlu};
+use serde::{Deserialize, Serialize};
+use server::CoapServer;
+use storage::{Saved, SharedStorage};
 
 type RequestId = u64;
-type SharedPending<D> = Arc<Mutex<HashMap<RequestId, oneshot::Sender<D>>>>;
+/// canYouAnswer is sent with the same request id to several skills
+type CanYouKey = (RequestId, String);
+type SharedPending<K, D> = Arc<Mutex<HashMap<K, Pending<D>>>>;
+type SharedSkills = Arc<SyncMutex<HashMap<String, ConnectedSkill>>>;
+
+/// What we know about a connected skill, kept in the storage (if any)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ConnectedSkill {
+    /// The human readable name given on connect
+    pub(crate) name: String,
+    /// The VAP version agreed on connect, use it to talk to older skills
+    pub(crate) version: Version,
+    /// Given on connect, every message of the skill carries it (see `AUTH_TOKEN_OPTION`),
+    /// only whoever knows it can connect again with this id
+    pub(crate) token: String,
+    /// The OSCORE security context of this connection, made on connect. Never
+    /// stored, restored skills have none until they connect again
+    #[serde(skip)]
+    pub(crate) oscore: Option<protection::SharedContext>,
+    /// The last intents accepted by the host, the skill doesn't need to send
+    /// them again when it connects back with its token
+    #[serde(default)]
+    pub(crate) intents: Option<MsgRegisterIntents>,
+    /// Sent on connect, the intents are only known on the next connect if it
+    /// sends the same one
+    #[serde(default)]
+    pub(crate) intents_digest: Option<String>,
+    /// When the last message from the skill arrived, restored skills have
+    /// until they miss their heartbeats to come back
+    #[serde(skip, default = "Instant::now")]
+    pub(crate) last_seen: Instant,
+    /// Loaded from the storage and not connected again since, those still
+    /// restored after the grace period are removed
+    #[serde(skip, default = "restored")]
+    pub(crate) restored: bool,
+}
+
+fn restored() -> bool {
+    true
+}
+
+/// A request waiting for an answer
+pub(crate) struct Pending<D> {
+    /// The skill the request was sent to, the only one that can answer it
+    pub(crate) skill_id: String,
+    /// The client that made the request
+    pub(crate) client_id: String,
+    pub(crate) sender: oneshot::Sender<D>,
+}
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("A Oneshot channel was closed")]
     ClosedChannel,
+
+    #[error("The skill didn't answer in time")]
+    Timeout,
+
+    #[error("The skill is not connected, or disconnected before answering")]
+    SkillGone,
+
+    #[error("The value of the slot {0} is not one the skill accepts")]
+    SlotValue(String),
+
+    #[error("Couldn't read or write a file")]
+    Io(#[from] std::io::Error),
+
+    #[error("A configuration file is not valid: {0}")]
+    Config(#[from] toml::de::Error),
+
+    #[error("Couldn't save the approvals: {0}")]
+    SaveApprovals(#[from] toml::ser::Error),
+
+    #[error("The stored skills are not valid: {0}")]
+    Storage(#[from] encoding::DecodeError),
+}
+
+/// How the registry behaves, the default accepts every skill and allows them everything
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The permissions of skills, see `Policy::from_file`
+    pub policy: Policy,
+    /// If present unknown skills can't connect until the user accepts them,
+    /// see `SkillRegisterMessage::Approval`
+    pub approvals: Option<Approvals>,
+    /// How much each skill can send, see `Limits::from_file`
+    pub limits: Limits,
+    /// If present connected skills are kept here and restored on startup,
+    /// see `FileStorage` and `SkillRegisterMessage::Restored`
+    pub storage: Option<Box<dyn Storage>>,
+    /// Seconds restored skills have to connect again before they are regarded
+    /// as gone, `RESTORE_GRACE_SECS` if not present
+    pub restore_grace_secs: Option<u32>,
+    /// If present skills have to send a heartbeat this often (in seconds), those
+    /// that miss `MISSED_HEARTBEATS` in a row are regarded as gone, see
+    /// `SkillRegisterMessage::Disconnected`
+    pub heartbeat_secs: Option<u32>,
+    /// How `SkillRegisterOut::arbitrate` ranks skills
+    pub arbitration: ArbitrationConfig,
+    /// If true the registry matches text against the utterances of skills, for
+    /// hosts without an NLU of their own, see `SkillRegisterOut::understand`
+    pub nlu: bool,
 }
 
 pub struct Response {
@@ -39,17 +164,196 @@ pub struct Response {
     pub payload: Vec<u8>,
 }
 
+impl Response {
+    /// A response with a VAP error as payload, the error code is taken from the status
+    pub fn error<S: Into<String>>(status: ResponseType, type_: S, object: Option<String>) -> Self {
+        let mut err = VapError::new(status_code(status), type_);
+        err.object = object;
+
+        Response {
+            status,
+            payload: encoding::to_vec(&err).unwrap(),
+        }
+    }
+}
+
 /// Will handle incoming and outgoing messages to and from the skills, also
 /// keeps account of the skills registered on the system.
 pub struct SkillRegister {
-    ip_address: String,
+    socket: std::net::UdpSocket,
     in_send: mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    pending_requests: SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
-    pending_can_you: SharedPending<f32>,
-    current_skills: Arc<SyncMutex<HashMap<String, ()>>>,
-    barrier: Arc<Barrier>,
-    _clnt_thrd: thread::JoinHandle<()>,
-    self_send: mpsc::Sender<(String, Vec<u8>)>,
+    state: State,
+    /// Requests for skills, notified to them by the server
+    self_recv: mpsc::Receiver<(String, Vec<u8>)>,
+}
+
+/// Everything shared by the handlers of incoming requests
+pub(crate) struct State {
+    pub(crate) current_skills: SharedSkills,
+    /// Ids of new skills whose connect is waiting for the host, nobody else
+    /// can take them meanwhile
+    pub(crate) connecting: SyncMutex<HashSet<String>>,
+    pub(crate) pending_requests:
+        SharedPending<RequestId, (Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
+    pub(crate) pending_can_you: SharedPending<CanYouKey, f32>,
+    pub(crate) policy: Arc<Policy>,
+    pub(crate) approvals: Option<SharedApprovals>,
+    pub(crate) limiter: SharedLimiter,
+    pub(crate) storage: Option<SharedStorage>,
+    /// Numbers the saves, so that an older one never replaces a newer one
+    pub(crate) saves: AtomicU64,
+    pub(crate) restore_grace: Duration,
+    pub(crate) heartbeat_secs: Option<u32>,
+    pub(crate) nlu: Option<SharedNlu>,
+}
+
+impl State {
+    /// Saves the connected skills, if there's a storage. Failing to do so
+    /// doesn't stop the registry, skills will just have to connect again after a restart.
+    /// The storage is written on a thread of its own, nothing waits for it.
+    pub(crate) fn persist(&self) {
+        let storage = match &self.storage {
+            Some(storage) => storage.clone(),
+            None => return,
+        };
+        let (number, data) = {
+            let skills = self.current_skills.lock().unwrap();
+            (self.saves.fetch_add(1, Ordering::SeqCst) + 1, encoding::to_vec(&*skills))
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Couldn't encode the connected skills: {}", e);
+                return;
+            }
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let mut saved = storage.lock().unwrap();
+            // A newer save got here first
+            if saved.last > number {
+                return;
+            }
+            match saved.storage.save(&data) {
+                Ok(()) => saved.last = number,
+                Err(e) => warn!("Couldn't save the connected skills: {}", e),
+            }
+        });
+    }
+
+    /// Removes the restored skills that didn't connect again, same as `remove_silent`
+    async fn remove_restored(&self) -> Vec<String> {
+        let restored = self
+            .current_skills
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| s.restored)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        self.remove(&restored).await
+    }
+
+    /// Removes the skills that missed too many heartbeats, along with their
+    /// pending requests (whoever waits for them gets an error). Returns their ids.
+    async fn remove_silent(&self, heartbeat_secs: u32) -> Vec<String> {
+        let max_silence = Duration::from_secs(u64::from(heartbeat_secs) * u64::from(MISSED_HEARTBEATS));
+        let silent = self
+            .current_skills
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| s.last_seen.elapsed() > max_silence)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        self.remove(&silent).await
+    }
+
+    /// Removes these skills along with their pending requests (whoever waits
+    /// for them gets an error). Returns the ids of those that were connected.
+    async fn remove(&self, skill_ids: &[String]) -> Vec<String> {
+        let gone: Vec<String> = {
+            let mut skills = self.current_skills.lock().unwrap();
+            let gone = skill_ids
+                .iter()
+                .filter(|id| skills.remove(*id).is_some())
+                .cloned()
+                .collect::<Vec<_>>();
+            if let Some(nlu) = &self.nlu {
+                let mut nlu = nlu.lock().unwrap();
+                for id in &gone {
+                    nlu.remove(id);
+                }
+            }
+            gone
+        };
+
+        if !gone.is_empty() {
+            self.drop_pending(&gone).await;
+            self.persist();
+        }
+
+        gone
+    }
+
+    /// Fails the requests pending for these skills, whoever waits for them
+    /// gets `Error::SkillGone`
+    pub(crate) async fn drop_pending(&self, skill_ids: &[String]) {
+        self.pending_requests
+            .lock()
+            .await
+            .retain(|_, p| !skill_ids.contains(&p.skill_id));
+        self.pending_can_you
+            .lock()
+            .await
+            .retain(|_, p| !skill_ids.contains(&p.skill_id));
+    }
+}
+
+/// Adds a pending request, dropping those nobody waits for anymore
+async fn add_pending<K: Eq + Hash, D>(pending: &SharedPending<K, D>, key: K, entry: Pending<D>) {
+    let mut pending = pending.lock().await;
+    // Whoever waited for these gave up (e.g: the future was dropped)
+    pending.retain(|_, p| !p.sender.is_canceled());
+    pending.insert(key, entry);
+}
+
+fn encode_request(msg: &MsgSkillRequest) -> Result<Vec<u8>, Error> {
+    encoding::to_vec(msg).map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Hands a pending request to the server, which sends it to the skill. If the
+/// server is gone no skill can get it, and it stops being pending.
+async fn send_request<K: Eq + Hash, D>(
+    self_send: &mut mpsc::Sender<(String, Vec<u8>)>,
+    pending: &SharedPending<K, D>,
+    key: &K,
+    skill_id: &str,
+    data: Vec<u8>,
+) -> Result<(), Error> {
+    if self_send.send((skill_id.to_string(), data)).await.is_err() {
+        pending.lock().await.remove(key);
+        return Err(Error::SkillGone);
+    }
+    Ok(())
+}
+
+/// Waits until the skill answers a pending request, or until `deadline`
+async fn wait_answer<K: Eq + Hash, D>(
+    pending: &SharedPending<K, D>,
+    key: K,
+    receiver: oneshot::Receiver<D>,
+    deadline: tokio::time::Instant,
+) -> Result<D, Error> {
+    match tokio::time::timeout_at(deadline, receiver).await {
+        Ok(Ok(answer)) => Ok(answer),
+        // The skill disconnected or was removed before answering
+        Ok(Err(_)) => Err(Error::SkillGone),
+        Err(_) => {
+            pending.lock().await.remove(&key);
+            Err(Error::Timeout)
+        }
+    }
 }
 
 /// A notification received from a skill, can contain data for different VAP clients
@@ -65,9 +369,30 @@ pub struct NotificationData {
     pub capabilities: Vec<structures::PlainCapability>,
 }
 
+/// A skill that was connected before the registry restarted
+#[derive(Clone, Debug)]
+pub struct RestoredSkill {
+    pub id: String,
+    pub name: String,
+    /// The VAP version agreed on connect
+    pub version: Version,
+    /// The last intents registered by the skill, if any
+    pub intents: Option<MsgRegisterIntents>,
+}
+
 /// A message received from a skill
 pub enum SkillRegisterMessage {
-    Connect(MsgConnect),
+    /// A skill that is not known wants to connect, ask the user and answer
+    /// through the request itself, the responder is not used.
+    Approval(ApprovalRequest),
+    /// Sent on startup for every skill kept in the storage, so that the host
+    /// can know about it again, the responder is not used.
+    Restored(RestoredSkill),
+    /// A skill stopped sending heartbeats or getting its requests and was
+    /// removed, its requests were failed. The responder is not used.
+    Disconnected(String),
+    /// A skill wants to connect, the version is the one agreed with it
+    Connect(MsgConnect, Version),
     RegisterIntents(MsgRegisterIntents),
     Notification(Notification),
     Query(MsgQuery),
@@ -82,67 +407,166 @@ fn respond(resp: Option<CoapResponse>, st: ResponseType, pl: Vec<u8>) -> Option<
     })
 }
 
+/// Answers with a VAP error, the code inside the error is taken from the status
+fn respond_error(
+    resp: Option<CoapResponse>,
+    st: ResponseType,
+    type_: &str,
+    object: Option<String>,
+) -> Option<CoapResponse> {
+    let err = Response::error(st, type_, object);
+    respond(resp, err.status, err.payload)
+}
+
+/// Answers with a VAP error, and tells the other side how many seconds to wait
+/// before trying again (as Max-Age)
+fn respond_retry(
+    resp: Option<CoapResponse>,
+    st: ResponseType,
+    type_: &str,
+    object: Option<String>,
+    retry_secs: u32,
+) -> Option<CoapResponse> {
+    respond_error(resp, st, type_, object).map(|mut c| {
+        // Max-Age is an unsigned integer without leading zeros
+        let max_age = retry_secs
+            .to_be_bytes()
+            .iter()
+            .skip_while(|b| **b == 0)
+            .copied()
+            .collect();
+        c.message.add_option(CoapOption::MaxAge, max_age);
+        c
+    })
+}
+
+/// Answers with an already made VAP error, the status is taken from its code
+fn respond_vap_error(resp: Option<CoapResponse>, err: VapError) -> Option<CoapResponse> {
+    let st = status_from_code(err.code);
+    respond(resp, st, encoding::to_vec(&err).unwrap())
+}
+
+/// Transforms a CoAP status into it's numeric form (e.g: 4.04 -> 404)
+fn status_code(st: ResponseType) -> u16 {
+    let raw: u8 = MessageClass::Response(st).into();
+    u16::from(raw >> 5) * 100 + u16::from(raw & 0x1F)
+}
+
+/// Transforms a numeric code into a CoAP status (e.g: 404 -> 4.04)
+fn status_from_code(code: u16) -> ResponseType {
+    let raw = ((code / 100) << 5) as u8 | (code % 100) as u8;
+    match MessageClass::from(raw) {
+        MessageClass::Response(st) => st,
+        _ => ResponseType::InternalServerError,
+    }
+}
+
 impl SkillRegister {
     /// Creates a new skill register, the skill register is divided into three parts:
     /// 1. The skill register task, which will handle everything behind the scenes, you just need to await on run().
     /// 2. The skill stream, which will receive all the messages from the skills.
     /// 3. The skill out, which you can use to send messages to the skills.
     /// # Arguments
-    /// * `port` - The port for the skill register to listen CoAP messages on.    
+    /// * `port` - The port for the skill register to listen CoAP messages on,
+    ///   0 picks a free one (see `local_addr`).
     pub fn new(port: u16) -> Result<(Self, SkillRegisterStream, SkillRegisterOut), Error> {
+        Self::with_policy(port, Policy::default())
+    }
+
+    /// Same as `new`, but skills can only use what `policy` allows them to.
+    /// # Arguments
+    /// * `port` - The port for the skill register to listen CoAP messages on.
+    /// * `policy` - The permissions of skills, see `Policy::from_file`.
+    pub fn with_policy(
+        port: u16,
+        policy: Policy,
+    ) -> Result<(Self, SkillRegisterStream, SkillRegisterOut), Error> {
+        Self::with_config(
+            port,
+            Config {
+                policy,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Same as `new`, but with everything that can be configured.
+    /// # Arguments
+    /// * `port` - The port for the skill register to listen CoAP messages on.
+    /// * `config` - How the registry behaves.
+    pub fn with_config(
+        port: u16,
+        config: Config,
+    ) -> Result<(Self, SkillRegisterStream, SkillRegisterOut), Error> {
+        let socket = std::net::UdpSocket::bind(("127.0.0.1", port))?;
+        let policy = Arc::new(config.policy);
+        let approvals = config.approvals.map(|a| Arc::new(SyncMutex::new(a)));
+        let limiter = Arc::new(SyncMutex::new(Limiter::new(config.limits)));
         let (in_send, in_recv) = mpsc::channel(20);
+        let mut storage = config.storage;
+        let restored = match &mut storage {
+            Some(storage) => match storage.load()? {
+                Some(data) => encoding::from_slice(&data)?,
+                None => HashMap::new(),
+            },
+            None => HashMap::new(),
+        };
+        let nlu = if config.nlu {
+            let mut nlu = TemplateNlu::default();
+            for intents in restored.values().filter_map(|s: &ConnectedSkill| s.intents.as_ref()) {
+                nlu.add(intents);
+            }
+            Some(Arc::new(SyncMutex::new(nlu)))
+        } else {
+            None
+        };
+        let current_skills = Arc::new(SyncMutex::new(restored));
         let pending_requests = Arc::new(Mutex::new(HashMap::new()));
         let pending_can_you = Arc::new(Mutex::new(HashMap::new()));
-        let barrier = Arc::new(Barrier::new(2));
-
-        let (self_send, mut self_recv) = mpsc::channel(20);
-        let barrier2 = barrier.clone();
-        let _clnt_thrd = thread::spawn(move || {
-            // In Linux we need a second thread to to send to ourselves, otherwise
-            // what would be a non-blocking operation, tries to block, which
-            // returns an error.
-            Runtime::new().unwrap().block_on(async move {
-                let ip_address = format!("127.0.0.1:{}", port);
-                let mut client = CoAPClient::new(&ip_address).unwrap();
-                barrier2.wait(); // Make sure we are not sending anything before the server is ready
-
-                loop {
-                    let (name, data): (String, Vec<u8>) = self_recv.next().await.unwrap();
-                    println!("Size: {}", data.len());
-                    let resp = client
-                        .request_path(
-                            &format!("vap/skillRegistry/skills/{}", name),
-                            Method::Put,
-                            Some(data),
-                            None,
-                        )
-                        .unwrap();
-                    assert_eq!(resp.get_status(), &coap_lite::ResponseType::Valid);
-                }
-            });
-        });
+        let (self_send, self_recv) = mpsc::channel(20);
 
         Ok((
             SkillRegister {
-                ip_address: format!("127.0.0.1:{}", port),
+                socket,
                 in_send,
-                pending_requests: pending_requests.clone(),
-                pending_can_you: pending_can_you.clone(),
-                current_skills: Arc::new(SyncMutex::new(HashMap::new())),
-                barrier,
-                _clnt_thrd,
-                self_send: self_send.clone(),
+                state: State {
+                    current_skills: current_skills.clone(),
+                    connecting: SyncMutex::new(HashSet::new()),
+                    pending_requests: pending_requests.clone(),
+                    pending_can_you: pending_can_you.clone(),
+                    policy: policy.clone(),
+                    approvals,
+                    limiter: limiter.clone(),
+                    storage: storage.map(|storage| Arc::new(SyncMutex::new(Saved {storage, last: 0}))),
+                    saves: AtomicU64::new(0),
+                    restore_grace: Duration::from_secs(
+                        config.restore_grace_secs.unwrap_or(RESTORE_GRACE_SECS).into(),
+                    ),
+                    heartbeat_secs: config.heartbeat_secs,
+                    nlu: nlu.clone(),
+                },
+                self_recv,
             },
             SkillRegisterStream { stream_in: in_recv },
             SkillRegisterOut {
+                current_skills,
                 pending_requests,
                 self_send,
                 pending_can_you,
+                policy,
+                limiter,
+                arbiter: Arbiter::new(config.arbitration),
+                nlu,
                 next_request: RefCell::new(0),
             },
         ))
     }
 
+    /// The address skills have to send their messages to
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
     /// Call this function and await it for the rest of the program, this handles
     /// sending and receiving messages from the skills. Stopping this means no more
     /// communication, and even dropped channels.
@@ -150,76 +574,159 @@ impl SkillRegister {
         async fn perform(
             request: CoapRequest<SocketAddr>,
             mut in_send: mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-            pending_requests: &SharedPending<(
-                Vec<PlainCapability>,
-                oneshot::Sender<RequestResponse>,
-            )>,
-            pending_can_you: &SharedPending<f32>,
-            current_skills: Arc<SyncMutex<HashMap<String, ()>>>,
-            mut self_send: mpsc::Sender<(String, Vec<u8>)>,
+            state: &State,
         ) -> Option<CoapResponse> {
-            match *request.get_method() {
-                Method::Get => method_handlers::on_get(request, &mut in_send, current_skills).await,
+            let (request, protection) = match protection::unprotect(&state.current_skills, request) {
+                Ok(unprotected) => unprotected,
+                Err(resp) => return resp,
+            };
+            let token = method_handlers::auth_token(&request);
+            let skill_id = method_handlers::seen(&state.current_skills, token.as_deref());
+            let request = match method_handlers::charge_limits(request, skill_id, state) {
+                Ok(request) => request,
+                Err(resp) => return protection::protect(resp, protection),
+            };
+            let token = token.as_deref();
+            let response = match *request.get_method() {
+                Method::Get => {
+                    method_handlers::on_get(request, token, &mut in_send, state).await
+                }
                 Method::Post => {
-                    method_handlers::on_post(
-                        request,
-                        &mut in_send,
-                        &mut self_send,
-                        &current_skills,
-                        pending_can_you,
-                        pending_requests,
-                    )
-                    .await
+                    method_handlers::on_post(request, token, &mut in_send, state).await
                 }
                 Method::Delete => {
-                    method_handlers::on_delete(request, &mut in_send, current_skills).await
+                    method_handlers::on_delete(request, token, &mut in_send, state).await
                 }
-                Method::Put =>
-                // Puts are needed so that an observe update is produced
-                {
-                    if request.get_path().starts_with("vap/skillRegistry/skill") {
-                        respond(request.response, coap_lite::ResponseType::Valid, vec![])
-                    }
-                    else {
-                        respond(request.response, coap_lite::ResponseType::MethodNotAllowed, vec![])
-                    }
-                }
-
                 _ => {
                     println!("request by other method");
-                    respond(
+                    let path = request.get_path();
+                    respond_error(
                         request.response,
                         coap_lite::ResponseType::MethodNotAllowed,
-                        vec![],
+                        vap_error::METHOD_NOT_ALLOWED,
+                        Some(path),
                     )
                 }
-            }
+            };
+            protection::protect(response, protection)
         }
 
-        let mut server = Server::new(&self.ip_address).unwrap();
-        server.enable_all_coap(0);
+        let SkillRegister {
+            socket,
+            in_send,
+            state,
+            mut self_recv,
+        } = self;
 
-        // The server is ready, we can start to send requests to itself
-        // This is added because of an issue with the client trying to acces the
-        // server too soon on Linux.
-        self.barrier.wait();
-        server
-            .run(|request| {
-                perform(
-                    request,
-                    self.in_send.clone(),
-                    &self.pending_requests,
-                    &self.pending_can_you,
-                    self.current_skills.clone(),
-                    self.self_send.clone(),
-                )
+        // Let the host know about the skills that were connected before a restart
+        let restored: Vec<_> = state
+            .current_skills
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, skill)| RestoredSkill {
+                id: id.clone(),
+                name: skill.name.clone(),
+                version: skill.version,
+                intents: skill.intents.clone(),
             })
-            .await
-            .unwrap();
+            .collect();
+        let mut restored_send = in_send.clone();
+        for skill in restored {
+            let (sender, _) = oneshot::channel();
+            if restored_send
+                .send((SkillRegisterMessage::Restored(skill), sender))
+                .await
+                .is_err()
+            {
+                warn!("The host is not listening, it won't know about the restored skills");
+                break;
+            }
+        }
+
+        let server = CoapServer::from_std(socket)?;
+        let serve = server.serve(|request| perform(request, in_send.clone(), &state));
+
+        // Removed skills stop being observed, and the host is told about it
+        let disconnected = |id: String| {
+            let mut in_send = in_send.clone();
+            let server = &server;
+            async move {
+                server.forget(&id);
+                let (sender, _) = oneshot::channel();
+                in_send
+                    .send((SkillRegisterMessage::Disconnected(id), sender))
+                    .await
+                    .unwrap();
+            }
+        };
+
+        // Requests for skills, sent straight to the skill observing them. Each
+        // one waits for its acknowledgement without holding up the others.
+        // Skills that don't get them are removed, they would time out anyway.
+        let notify = async {
+            let server = &server;
+            let mut notifying = FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    received = self_recv.next() => match received {
+                        Some((skill_id, data)) => notifying.push(async move {
+                            (server.notify(&skill_id, data).await, skill_id)
+                        }),
+                        None => break,
+                    },
+                    Some((delivered, skill_id)) = notifying.next() => {
+                        if !delivered {
+                            for id in state.remove(&[skill_id]).await {
+                                println!("Skill {} is not getting its requests, removed", id);
+                                disconnected(id).await;
+                            }
+                        }
+                    },
+                }
+            }
+        };
+
+        // Restored skills that don't connect again in time are removed
+        let expire_restored = async {
+            tokio::time::sleep(state.restore_grace).await;
+            for id in state.remove_restored().await {
+                println!("Skill {} didn't connect again after the restart, removed", id);
+                disconnected(id).await;
+            }
+        };
+
+        // Skills that go silent are removed
+        let watch_heartbeats = async {
+            if let Some(heartbeat_secs) = state.heartbeat_secs {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(heartbeat_secs.into()));
+                loop {
+                    interval.tick().await;
+                    for id in state.remove_silent(heartbeat_secs).await {
+                        println!("Skill {} stopped sending heartbeats, removed", id);
+                        disconnected(id).await;
+                    }
+                }
+            }
+        };
+
+        join3(serve, notify, join(expire_restored, watch_heartbeats)).await;
         Ok(())
     }
 }
 
+/// How confident skills are in being able to answer a request
+#[derive(Debug)]
+pub struct Confidences {
+    /// The id the request was sent with, the same for every skill
+    pub request_id: u64,
+    /// The confidence of each skill that answered in time
+    pub answered: HashMap<String, f32>,
+    /// The skills that didn't answer, and why
+    pub missing: HashMap<String, Error>,
+}
+
 /// Whether a notification could be handled or some problem arised
 #[derive(Debug, Clone)]
 pub struct NotificationResponse {
@@ -237,75 +744,126 @@ pub struct RequestResponse {
 
 /// An object for sending messages to skills
 pub struct SkillRegisterOut {
-    pending_requests: SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
-    pending_can_you: SharedPending<f32>,
+    current_skills: SharedSkills,
+    pending_requests: SharedPending<RequestId, (Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
+    pending_can_you: SharedPending<CanYouKey, f32>,
+    policy: Arc<Policy>,
+    limiter: SharedLimiter,
+    arbiter: Arbiter,
+    nlu: Option<SharedNlu>,
     next_request: RefCell<RequestId>,
     self_send: mpsc::Sender<(String, Vec<u8>)>,
 }
 
 impl SkillRegisterOut {
-    /// Returns how confident skills registered for some request are in being able to handle it
+    /// Asks every skill at once how confident it is in being able to answer
+    /// the request. Skills that don't answer within `timeout` are left out.
     pub async fn skills_answerable(
         &mut self,
         ids: &[String],
         request: RequestData,
         client: ClientData,
-    ) -> Vec<MsgNotification> {
-        // TODO: Respond to the notification
-        async fn send_msg(
-            self_send: &mut mpsc::Sender<(String, Vec<u8>)>,
-            id: &str,
-            request: RequestData,
-            request_id: RequestId,
-            client: ClientData,
-            pending_can_you: &SharedPending<f32>,
-        ) -> Result<MsgNotification, Error> {
-            let msg = MsgSkillRequest {
-                client,
-                request_id,
-                request,
-            };
-            let data = rmp_serde::to_vec(&msg).unwrap();
-            self_send.send((id.into(), data)).await.unwrap();
-
-            let (sender, receiver) = oneshot::channel();
-            pending_can_you.lock().await.insert(request_id, sender);
-            let a = receiver.await.unwrap();
+        timeout: Duration,
+    ) -> Confidences {
+        let request_id = self.get_id();
+        let deadline = tokio::time::Instant::now() + timeout;
+        let (current_skills, policy, pending_can_you) =
+            (&self.current_skills, &self.policy, &self.pending_can_you);
+        let asks = ids.iter().map(|id| {
+            let mut self_send = self.self_send.clone();
+            let mut request = request.clone();
+            let client = client.clone();
+            async move {
+                let intents = match current_skills.lock().unwrap().get(id) {
+                    Some(skill) => skill.intents.clone(),
+                    None => return Err(Error::SkillGone),
+                };
+                if let Some(intents) = intents {
+                    nlu::resolve_slots(&intents, &mut request).map_err(Error::SlotValue)?;
+                }
 
-            Ok(MsgNotification {
-                skill_id: id.to_string(),
-                data: vec![msg_notification::Data::CanYouAnswer {
+                request.capabilities = policy.filter_received(id, request.capabilities);
+                let client_id = client.system_id.clone();
+                let data = encode_request(&MsgSkillRequest {
+                    client,
                     request_id,
-                    confidence: a,
-                }],
-            })
-        }
+                    request,
+                })?;
+                let key = (request_id, id.clone());
+                let (sender, receiver) = oneshot::channel();
+                add_pending(
+                    pending_can_you,
+                    key.clone(),
+                    Pending {
+                        skill_id: id.clone(),
+                        client_id,
+                        sender,
+                    },
+                )
+                .await;
+                send_request(&mut self_send, pending_can_you, &key, id, data).await?;
 
-        let mut answers = Vec::new();
-        let new_id = self.get_id();
-        for id in ids {
-            match send_msg(
-                &mut self.self_send,
-                id,
-                request.clone(),
-                new_id,
-                client.clone(),
-                &self.pending_can_you,
-            )
-            .await
-            {
-                Ok(resp) => {
-                    println!("We received the notification: {:?}", resp);
-                    answers.push(resp);
+                wait_answer(pending_can_you, key, receiver, deadline).await
+            }
+        });
+
+        let mut confidences = Confidences {
+            request_id,
+            answered: HashMap::new(),
+            missing: HashMap::new(),
+        };
+        for (id, result) in ids.iter().zip(join_all(asks).await) {
+            match result {
+                Ok(confidence) => {
+                    confidences.answered.insert(id.clone(), confidence);
                 }
                 Err(e) => {
-                    // TODO: What to do with the errors?
-                    println!("Error receiving notifications: {:?}", e);
+                    println!("Skill {} can't tell whether it can answer: {}", id, e);
+                    confidences.missing.insert(id.clone(), e);
                 }
             }
         }
 
-        answers
+        confidences
+    }
+
+    /// Chooses which skill should answer, out of their confidences (see
+    /// `skills_answerable`). Skills used recently are preferred.
+    pub fn arbitrate(&self, confidences: &Confidences) -> Choice {
+        let skills = self.current_skills.lock().unwrap();
+        self.arbiter.choose(&confidences.answered, |id| {
+            skills.get(id).map(|s| s.name.clone()).unwrap_or_else(|| id.to_string())
+        })
+    }
+
+    /// Matches what the user said against the utterances of every skill, in
+    /// the language of `locale`. `None` if nothing matched or `Config::nlu` is false.
+    pub fn understand(&self, input: &str, locale: &str) -> Option<Understood> {
+        self.nlu.as_ref()?.lock().unwrap().understand(input, locale)
+    }
+
+    /// The VAP version agreed with a connected skill, requests sent to it
+    /// should follow that version
+    pub fn skill_version(&self, skill_id: &str) -> Option<Version> {
+        self.current_skills
+            .lock()
+            .unwrap()
+            .get(skill_id)
+            .map(|s| s.version)
+    }
+
+    /// How many times each skill went over its limits, skills that never did
+    /// are not present
+    pub fn rate_limited(&self) -> HashMap<String, LimitStats> {
+        self.limiter.lock().unwrap().stats()
+    }
+
+    /// The intents of a connected skill, `Error::SkillGone` if not connected
+    fn skill_intents(&self, skill_id: &str) -> Result<Option<MsgRegisterIntents>, Error> {
+        match self.current_skills.lock().unwrap().get(skill_id) {
+            Some(skill) => Ok(skill.intents.clone()),
+            None => Err(Error::SkillGone),
+        }
     }
 
     fn get_id(&self) -> RequestId {
@@ -316,23 +874,44 @@ impl SkillRegisterOut {
         id
     }
 
-    /// Sends a request to a skill
+    /// Sends a request to a skill and waits for its answer, up to `timeout`.
+    /// Dropping the returned future cancels the request.
     pub async fn activate_skill(
         &mut self,
         name: String,
         mut msg: MsgSkillRequest,
+        timeout: Duration,
     ) -> Result<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>), Error> {
-        // TODO: Respond to the notification
+        if let Some(intents) = self.skill_intents(&name)? {
+            nlu::resolve_slots(&intents, &mut msg.request).map_err(Error::SlotValue)?;
+        }
+
         let req_id = self.get_id();
         msg.request_id = req_id;
-        let (sender, receiver) = oneshot::channel();
-        let data = rmp_serde::to_vec(&msg).unwrap();
-        self.self_send.send((name, data)).await.unwrap();
+        // Capabilities the skill is not allowed to see never leave the registry
+        msg.request.capabilities = self
+            .policy
+            .filter_received(&name, msg.request.capabilities);
+        let client_id = msg.client.system_id.clone();
+        let data = encode_request(&msg)?;
 
-        self.pending_requests.lock().await.insert(req_id, sender);
+        // Pending before sending, so that even the fastest answer finds it
+        let (sender, receiver) = oneshot::channel();
+        add_pending(
+            &self.pending_requests,
+            req_id,
+            Pending {
+                skill_id: name.clone(),
+                client_id,
+                sender,
+            },
+        )
+        .await;
+        self.arbiter.used(&name);
+        send_request(&mut self.self_send, &self.pending_requests, &req_id, &name, data).await?;
 
-        let resp_data = receiver.await.unwrap();
-        Ok(resp_data)
+        let deadline = tokio::time::Instant::now() + timeout;
+        wait_answer(&self.pending_requests, req_id, receiver, deadline).await
     }
 }
 
@@ -355,3 +934,358 @@ impl SkillRegisterStream {
         Ok(self.stream_in.next().await.unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{connected_skill, request};
+    use coap_lite::{MessageType, Packet};
+    use futures::future::join;
+    use std::net::UdpSocket;
+    use std::thread;
+    use vap_common_skill::structures::msg_skill_request::RequestDataKind;
+
+    const LONG: Duration = Duration::from_secs(10);
+
+    fn coap_request(method: Method, path: &str, payload: Vec<u8>, token: Option<&str>) -> CoapRequest<SocketAddr> {
+        let mut request = CoapRequest::new();
+        request.message.header.set_type(MessageType::Confirmable);
+        request.set_method(method);
+        request.set_path(path);
+        request.message.payload = payload;
+        if let Some(token) = token {
+            request.message.add_option(CoapOption::Unknown(AUTH_TOKEN_OPTION), token.into());
+        }
+        request
+    }
+
+    fn new_socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    /// Sends a request to the registry, answers with the next message it sends us
+    fn exchange(socket: &UdpSocket, port: u16, request: &CoapRequest<SocketAddr>) -> Packet {
+        socket.send_to(&request.message.to_bytes().unwrap(), ("127.0.0.1", port)).unwrap();
+        receive(socket)
+    }
+
+    fn receive(socket: &UdpSocket) -> Packet {
+        let mut buf = [0; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        Packet::from_bytes(&buf[..len]).unwrap()
+    }
+
+    /// A registry where every skill in `ids` is connected, their token is their id
+    fn with_skills(ids: &[&str]) -> (SkillRegister, SkillRegisterStream, SkillRegisterOut) {
+        let (reg, stream, out) = SkillRegister::new(0).unwrap();
+        for id in ids {
+            let mut skill = connected_skill(id);
+            skill.token = id.to_string();
+            reg.state.current_skills.lock().unwrap().insert(id.to_string(), skill);
+        }
+        (reg, stream, out)
+    }
+
+    fn request_data() -> RequestData {
+        RequestData {
+            type_: RequestDataKind::CanAnswer,
+            intent: "hello".into(),
+            locale: "en-US".into(),
+            input: None,
+            slots: vec![],
+            capabilities: vec![],
+            event: None,
+        }
+    }
+
+    fn client() -> ClientData {
+        ClientData {system_id: "client".into(), capabilities: vec![]}
+    }
+
+    /// The next request sent to a skill, and to which
+    async fn next_request(reg: &mut SkillRegister) -> (String, MsgSkillRequest) {
+        let (id, data) = reg.self_recv.next().await.unwrap();
+        (id, encoding::from_slice(&data).unwrap())
+    }
+
+    /// Answers a canYouAnswer as the skill `id` would
+    async fn can_you_answer(reg: &SkillRegister, id: &str, request_id: RequestId, confidence: f32) {
+        let request = request(Method::Post, "vap/skillRegistry/notification", &MsgNotification {
+            skill_id: id.into(),
+            data: vec![msg_notification::Data::CanYouAnswer {request_id, confidence}],
+        });
+        method_handlers::on_post(request, Some(id), &mut reg.in_send.clone(), &reg.state).await;
+    }
+
+    #[tokio::test]
+    async fn slow_skills_time_out() {
+        let (mut reg, _stream, mut out) = with_skills(&["com.example.fast", "com.example.slow"]);
+        let ids = vec!["com.example.fast".to_string(), "com.example.slow".to_string()];
+        let ask = out.skills_answerable(&ids, request_data(), client(), Duration::from_millis(200));
+        let skills = async {
+            for _ in 0..2 {
+                let (id, msg) = next_request(&mut reg).await;
+                if id == "com.example.fast" {
+                    can_you_answer(&reg, &id, msg.request_id, 0.5).await;
+                }
+            }
+        };
+
+        let (confidences, ()) = join(ask, skills).await;
+        assert_eq!(confidences.answered.get("com.example.fast"), Some(&0.5));
+        assert!(matches!(confidences.missing.get("com.example.slow"), Some(Error::Timeout)));
+        assert!(out.pending_can_you.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_go_to_their_skill() {
+        let (mut reg, _stream, mut out) = with_skills(&["com.example.a", "com.example.b"]);
+        let ids = vec!["com.example.a".to_string(), "com.example.b".to_string()];
+        let ask = out.skills_answerable(&ids, request_data(), client(), LONG);
+        let skills = async {
+            let (first, msg) = next_request(&mut reg).await;
+            let (second, other) = next_request(&mut reg).await;
+            // The same request for both, answered in the opposite order
+            assert_eq!(msg.request_id, other.request_id);
+            can_you_answer(&reg, &second, msg.request_id, 0.9).await;
+            can_you_answer(&reg, &first, msg.request_id, 0.2).await;
+            (first, second)
+        };
+
+        let (confidences, (first, second)) = join(ask, skills).await;
+        assert_eq!(confidences.answered[&first], 0.2);
+        assert_eq!(confidences.answered[&second], 0.9);
+        assert!(confidences.missing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_requests_are_pruned() {
+        let (_reg, _stream, mut out) = with_skills(&["com.example.test"]);
+        let msg = || MsgSkillRequest {request_id: 0, client: client(), request: request_data()};
+
+        // Given up on before the skill answered
+        let activate = out.activate_skill("com.example.test".into(), msg(), LONG);
+        assert!(tokio::time::timeout(Duration::from_millis(50), activate).await.is_err());
+        assert_eq!(out.pending_requests.lock().await.len(), 1);
+
+        // Only the new one is left
+        let activate = out.activate_skill("com.example.test".into(), msg(), LONG);
+        assert!(tokio::time::timeout(Duration::from_millis(50), activate).await.is_err());
+        let pending = out.pending_requests.lock().await;
+        assert_eq!(pending.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[tokio::test]
+    async fn requests_fail_once_the_server_is_gone() {
+        let (reg, _stream, mut out) = with_skills(&["com.example.test"]);
+        drop(reg);
+
+        let request = MsgSkillRequest {request_id: 0, client: client(), request: request_data()};
+        let result = out.activate_skill("com.example.test".into(), request, LONG).await;
+        assert!(matches!(result, Err(Error::SkillGone)));
+        assert!(out.pending_requests.lock().await.is_empty());
+
+        let ids = vec!["com.example.test".to_string()];
+        let confidences = out.skills_answerable(&ids, request_data(), client(), LONG).await;
+        assert!(matches!(confidences.missing.get("com.example.test"), Some(Error::SkillGone)));
+        assert!(out.pending_can_you.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn skills_observe_and_answer_requests() {
+        const ID: &str = "com.example.test";
+        let (reg, mut stream, mut out) = SkillRegister::new(0).unwrap();
+        let port = reg.local_addr().unwrap().port();
+        let (observing, observed) = std::sync::mpsc::channel();
+
+        let skill = thread::spawn(move || {
+            let socket = new_socket();
+            let connect = encoding::to_vec(&MsgConnect {
+                id: ID.into(),
+                name: "Test".into(),
+                vap_version: VAP_VERSION.to_string(),
+                min_vap_version: None,
+                unique_authentication_token: None,
+                intents_digest: None,
+            }).unwrap();
+            let resp = exchange(&socket, port, &coap_request(Method::Post, "vap/skillRegistry/connect", connect, None));
+            assert_eq!(resp.header.code, MessageClass::Response(ResponseType::Created));
+            let resp: MsgConnectResponse = encoding::from_slice(&resp.payload).unwrap();
+            let token = resp.unique_authentication_token.unwrap();
+
+            let mut observe = coap_request(Method::Get, &format!("vap/skillRegistry/skills/{}", ID), vec![], Some(&token));
+            observe.message.set_token(vec![7]);
+            observe.set_observe_flag(coap_lite::ObserveOption::Register);
+            let resp = exchange(&socket, port, &observe);
+            assert_eq!(resp.header.code, MessageClass::Response(ResponseType::Content));
+            observing.send(()).unwrap();
+
+            let notification = receive(&socket);
+            assert_eq!(notification.get_token(), &[7]);
+            let mut ack = Packet::new();
+            ack.header.set_type(MessageType::Acknowledgement);
+            ack.header.code = MessageClass::Empty;
+            ack.header.message_id = notification.header.message_id;
+            socket.send_to(&ack.to_bytes().unwrap(), ("127.0.0.1", port)).unwrap();
+            let request: MsgSkillRequest = encoding::from_slice(&notification.payload).unwrap();
+            assert_eq!(request.request.intent, "hello");
+
+            let answer = encoding::to_vec(&MsgNotification {
+                skill_id: ID.into(),
+                data: vec![msg_notification::Data::Requested {request_id: request.request_id, capabilities: vec![]}],
+            }).unwrap();
+            let resp = exchange(&socket, port, &coap_request(Method::Post, "vap/skillRegistry/notification", answer, Some(&token)));
+            let resp: MsgNotificationResponse = encoding::from_slice(&resp.payload).unwrap();
+            assert!(matches!(resp.data[..], [msg_notification_response::Data::Requested {code: 204, ..}]));
+        });
+
+        let host = async {
+            loop {
+                let (msg, responder) = stream.recv().await.unwrap();
+                assert!(matches!(msg, SkillRegisterMessage::Connect(..)));
+                let payload = encoding::to_vec(&MsgConnectResponse {
+                    langs: vec![],
+                    vap_version: None,
+                    unique_authentication_token: None,
+                    oscore_id_context: None,
+                    intents_known: false,
+                    heartbeat_secs: None,
+                }).unwrap();
+                responder.send(Response {status: ResponseType::Created, payload}).ok().unwrap();
+            }
+        };
+
+        let activate = async {
+            while observed.try_recv().is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let mut request = request_data();
+            request.type_ = RequestDataKind::Intent;
+            let msg = MsgSkillRequest {request_id: 0, client: client(), request};
+            let (capabilities, responder) = out.activate_skill(ID.into(), msg, LONG).await.unwrap();
+            assert!(capabilities.is_empty());
+            responder.send(RequestResponse {code: 204}).unwrap();
+
+            while !skill.is_finished() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        let done = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                r = reg.run() => panic!("The registry stopped: {:?}", r),
+                _ = host => {},
+                _ = activate => {},
+            }
+        });
+        done.await.expect("The skill didn't get its request");
+        skill.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn heartbeats_keep_skills_connected() {
+        let config = Config {heartbeat_secs: Some(1), ..Default::default()};
+        let (reg, _stream, out) = SkillRegister::with_config(0, config).unwrap();
+        let long_ago = Instant::now() - Duration::from_secs(10);
+        for id in ["com.example.alive", "com.example.silent"] {
+            let mut skill = connected_skill(id);
+            skill.token = id.into();
+            skill.last_seen = long_ago;
+            reg.state.current_skills.lock().unwrap().insert(id.into(), skill);
+        }
+        let (sender, mut receiver) = oneshot::channel();
+        reg.state.pending_requests.lock().await.insert(0, Pending {
+            skill_id: "com.example.silent".into(),
+            client_id: "client".into(),
+            sender,
+        });
+
+        // Any message carrying its token counts, wherever it comes from
+        let alive = method_handlers::seen(&reg.state.current_skills, Some("com.example.alive"));
+        assert_eq!(alive.as_deref(), Some("com.example.alive"));
+
+        assert_eq!(reg.state.remove_silent(1).await, ["com.example.silent"]);
+        assert!(out.skill_version("com.example.alive").is_some());
+        assert!(out.skill_version("com.example.silent").is_none());
+        // Whoever waited for the silent skill gets an error
+        assert!(receiver.try_recv().is_err());
+        assert!(reg.state.pending_requests.lock().await.is_empty());
+    }
+
+    /// Keeps what is saved in memory, where the test can look at it
+    #[derive(Clone, Debug, Default)]
+    struct MemoryStorage(Arc<SyncMutex<Option<Vec<u8>>>>);
+
+    impl Storage for MemoryStorage {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, Error> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn save(&mut self, data: &[u8]) -> Result<(), Error> {
+            *self.0.lock().unwrap() = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn restored_skills_expire_unless_they_connect_again() {
+        let storage = MemoryStorage::default();
+        let skills: HashMap<_, _> = ["com.example.back", "com.example.gone"]
+            .iter()
+            .map(|id| (id.to_string(), connected_skill(id)))
+            .collect();
+        *storage.0.lock().unwrap() = Some(encoding::to_vec(&skills).unwrap());
+        let config = Config {storage: Some(Box::new(storage.clone())), ..Default::default()};
+        let (reg, _stream, out) = SkillRegister::with_config(0, config).unwrap();
+        // Same as connecting again
+        reg.state.current_skills.lock().unwrap().get_mut("com.example.back").unwrap().restored = false;
+
+        assert_eq!(reg.state.remove_restored().await, ["com.example.gone"]);
+        assert!(out.skill_version("com.example.back").is_some());
+        assert!(out.skill_version("com.example.gone").is_none());
+
+        // Saved without waiting for it
+        let saved = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let saved: HashMap<String, ConnectedSkill> =
+                    encoding::from_slice(storage.0.lock().unwrap().as_ref().unwrap()).unwrap();
+                if saved.len() == 1 {
+                    break saved;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(saved.await.unwrap().contains_key("com.example.back"));
+    }
+
+    #[tokio::test]
+    async fn skills_not_getting_their_requests_are_removed() {
+        const ID: &str = "com.example.test";
+        let (reg, mut stream, mut out) = SkillRegister::new(0).unwrap();
+        reg.state.current_skills.lock().unwrap().insert(ID.into(), connected_skill("Test"));
+
+        // It never observed its requests
+        let activate = async {
+            let msg = MsgSkillRequest {request_id: 0, client: client(), request: request_data()};
+            let result = out.activate_skill(ID.into(), msg, LONG).await;
+            assert!(matches!(result, Err(Error::SkillGone)));
+            assert!(out.skill_version(ID).is_none());
+            loop {
+                if let (SkillRegisterMessage::Disconnected(id), _) = stream.recv().await.unwrap() {
+                    assert_eq!(id, ID);
+                    break;
+                }
+            }
+        };
+
+        let done = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                r = reg.run() => panic!("The registry stopped: {:?}", r),
+                _ = activate => {},
+            }
+        });
+        done.await.expect("The request was not failed");
+    }
+}
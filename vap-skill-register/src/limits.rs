@@ -0,0 +1,360 @@
+//! How much each skill can send, so that a misbehaving skill can't flood the
+//! registry. Limits are token buckets, written as TOML:
+//!
+//! ```toml
+//! [default]
+//! messages = { burst = 20, per_second = 5.0 }
+//! bytes = { burst = 65536, per_second = 16384.0 }
+//! standalone = { burst = 5, per_second = 0.5 }
+//!
+//! [skills."com.example.radio"]
+//! bytes = { burst = 1048576, per_second = 262144.0 }
+//!
+//! [unauthenticated]
+//! messages = { burst = 10, per_second = 1.0 }
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::Error;
+
+pub(crate) type SharedLimiter = Arc<SyncMutex<Limiter>>;
+
+/// A token bucket: up to `burst` can be spent at once, and it fills again at
+/// `per_second`
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_second: f32,
+}
+
+/// The limits of a skill, `None` means no limit
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SkillLimits {
+    /// Messages of any kind
+    pub messages: Option<Rate>,
+    /// Bytes of payload, messages bigger than the burst need the whole bucket
+    pub bytes: Option<Rate>,
+    /// Standalone notifications, for each client. Several for the same client
+    /// in one message need the whole bucket at most, as with `bytes`
+    pub standalone: Option<Rate>,
+}
+
+/// The limits of every skill
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Limits {
+    /// Used for skills not present in `skills`
+    #[serde(default)]
+    pub default: SkillLimits,
+    #[serde(default)]
+    pub skills: HashMap<String, SkillLimits>,
+    /// Used for messages without a valid token (e.g: connects), all of them
+    /// share the same buckets since senders are not tracked by address (see
+    /// GENERAL.MD). `standalone` is not used here.
+    #[serde(default)]
+    pub unauthenticated: SkillLimits,
+}
+
+impl FromStr for Limits {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+impl Limits {
+    /// Reads the limits from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    fn skill(&self, skill_id: &str) -> &SkillLimits {
+        self.skills.get(skill_id).unwrap_or(&self.default)
+    }
+}
+
+/// How many times a skill went over each of its limits
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LimitStats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub standalone: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f32,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: &Rate, now: Instant) -> Self {
+        Bucket {
+            tokens: rate.burst as f32,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst as f32);
+        self.last = now;
+    }
+
+    /// Seconds until `amount` can be taken, `None` if it can be taken right now
+    fn wait(&self, rate: &Rate, amount: f32) -> Option<u32> {
+        let missing = amount.min(rate.burst as f32) - self.tokens;
+        if missing <= 0.0 {
+            None
+        } else if rate.per_second > 0.0 {
+            Some((missing / rate.per_second).ceil() as u32)
+        } else {
+            Some(u32::MAX)
+        }
+    }
+
+    fn take(&mut self, rate: &Rate, amount: f32) {
+        self.tokens -= amount.min(rate.burst as f32);
+    }
+}
+
+#[derive(Debug, Default)]
+struct SkillBuckets {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    standalone: HashMap<String, Bucket>,
+}
+
+impl SkillBuckets {
+    /// Charges a message with a payload of `bytes`, only if every bucket has enough
+    fn charge(&mut self, limits: &SkillLimits, stats: &mut LimitStats, bytes: usize, now: Instant) -> Result<(), u32> {
+        let mut wait = None;
+        if let Some(rate) = &limits.messages {
+            let bucket = self.messages.get_or_insert_with(|| Bucket::new(rate, now));
+            bucket.refill(rate, now);
+            if let Some(w) = bucket.wait(rate, 1.0) {
+                stats.messages += 1;
+                wait = Some(w);
+            }
+        }
+        if let Some(rate) = &limits.bytes {
+            let bucket = self.bytes.get_or_insert_with(|| Bucket::new(rate, now));
+            bucket.refill(rate, now);
+            if let Some(w) = bucket.wait(rate, bytes as f32) {
+                stats.bytes += 1;
+                wait = wait.max(Some(w));
+            }
+        }
+
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        if let (Some(rate), Some(bucket)) = (&limits.messages, &mut self.messages) {
+            bucket.take(rate, 1.0);
+        }
+        if let (Some(rate), Some(bucket)) = (&limits.bytes, &mut self.bytes) {
+            bucket.take(rate, bytes as f32);
+        }
+
+        Ok(())
+    }
+}
+
+/// Senders over their limits are warned about at most this often
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the buckets of every skill
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    limits: Limits,
+    buckets: HashMap<String, SkillBuckets>,
+    unauthenticated: SkillBuckets,
+    stats: HashMap<String, LimitStats>,
+    /// When each sender was last warned about, `None` is for those without a
+    /// valid token
+    warned: HashMap<Option<String>, Instant>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Limiter {
+            limits,
+            buckets: HashMap::new(),
+            unauthenticated: SkillBuckets::default(),
+            stats: HashMap::new(),
+            warned: HashMap::new(),
+        }
+    }
+
+    /// Charges a message with a payload of `bytes` to the skill. If the skill
+    /// is over its limits nothing is charged, and the seconds to wait are returned.
+    pub(crate) fn check_message(&mut self, skill_id: &str, bytes: usize) -> Result<(), u32> {
+        self.check_message_at(skill_id, bytes, Instant::now())
+    }
+
+    fn check_message_at(&mut self, skill_id: &str, bytes: usize, now: Instant) -> Result<(), u32> {
+        let limits = self.limits.skill(skill_id);
+        let buckets = self.buckets.entry(skill_id.to_string()).or_default();
+        let mut over = LimitStats::default();
+        let charged = buckets.charge(limits, &mut over, bytes, now);
+        if charged.is_err() {
+            let stats = self.stats.entry(skill_id.to_string()).or_default();
+            stats.messages += over.messages;
+            stats.bytes += over.bytes;
+        }
+
+        charged
+    }
+
+    /// Charges a message without a valid token, same as `check_message`
+    pub(crate) fn check_unauthenticated(&mut self, bytes: usize) -> Result<(), u32> {
+        self.check_unauthenticated_at(bytes, Instant::now())
+    }
+
+    fn check_unauthenticated_at(&mut self, bytes: usize, now: Instant) -> Result<(), u32> {
+        // Not counted in the stats, those are for skills
+        let limits = &self.limits.unauthenticated;
+        self.unauthenticated.charge(limits, &mut LimitStats::default(), bytes, now)
+    }
+
+    /// Charges the standalone notifications of a message (one for each client
+    /// in `client_ids`) to the skill, same as `check_message`
+    pub(crate) fn check_standalone(&mut self, skill_id: &str, client_ids: &[&str]) -> Result<(), u32> {
+        self.check_standalone_at(skill_id, client_ids, Instant::now())
+    }
+
+    fn check_standalone_at(&mut self, skill_id: &str, client_ids: &[&str], now: Instant) -> Result<(), u32> {
+        let rate = match self.limits.skill(skill_id).standalone {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+
+        let mut counts: HashMap<&str, f32> = HashMap::new();
+        for client_id in client_ids {
+            *counts.entry(client_id).or_default() += 1.0;
+        }
+
+        let buckets = &mut self.buckets.entry(skill_id.to_string()).or_default().standalone;
+        let mut wait = None;
+        for (client_id, count) in &counts {
+            let bucket = buckets
+                .entry(client_id.to_string())
+                .or_insert_with(|| Bucket::new(&rate, now));
+            bucket.refill(&rate, now);
+            wait = wait.max(bucket.wait(&rate, *count));
+        }
+
+        if let Some(wait) = wait {
+            self.stats.entry(skill_id.to_string()).or_default().standalone += 1;
+            return Err(wait);
+        }
+
+        for (client_id, count) in &counts {
+            if let Some(bucket) = buckets.get_mut(*client_id) {
+                bucket.take(&rate, *count);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> HashMap<String, LimitStats> {
+        self.stats.clone()
+    }
+
+    /// Whether a sender that went over its limits should be warned about, so
+    /// that a flood doesn't flood the logs too
+    pub(crate) fn should_warn(&mut self, skill_id: Option<&str>) -> bool {
+        self.should_warn_at(skill_id, Instant::now())
+    }
+
+    fn should_warn_at(&mut self, skill_id: Option<&str>, now: Instant) -> bool {
+        let key = skill_id.map(str::to_string);
+        match self.warned.get(&key) {
+            Some(last) if now.saturating_duration_since(*last) < WARN_INTERVAL => false,
+            _ => {
+                self.warned.insert(key, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMITS: &str = r#"
+        [default]
+        messages = { burst = 2, per_second = 1.0 }
+        bytes = { burst = 100, per_second = 50.0 }
+        standalone = { burst = 1, per_second = 0.5 }
+    "#;
+
+    #[test]
+    fn limits_and_refills() {
+        let mut limiter = Limiter::new(LIMITS.parse().unwrap());
+        let start = Instant::now();
+        assert_eq!(limiter.check_message_at("com.example.test", 10, start), Ok(()));
+        assert_eq!(limiter.check_message_at("com.example.test", 10, start), Ok(()));
+        assert_eq!(limiter.check_message_at("com.example.test", 10, start), Err(1));
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_message_at("com.example.test", 10, later), Ok(()));
+
+        // A big message leaves too few bytes for the next one
+        assert_eq!(limiter.check_message_at("com.example.other", 90, start), Ok(()));
+        assert_eq!(limiter.check_message_at("com.example.other", 40, start), Err(1));
+
+        assert_eq!(limiter.check_standalone_at("com.example.test", &["kitchen"], start), Ok(()));
+        assert_eq!(limiter.check_standalone_at("com.example.test", &["kitchen"], start), Err(2));
+        assert_eq!(limiter.check_standalone_at("com.example.test", &["bedroom"], start), Ok(()));
+
+        let stats = limiter.stats();
+        assert_eq!(stats["com.example.test"], LimitStats { messages: 1, bytes: 0, standalone: 1 });
+        assert_eq!(stats["com.example.other"].bytes, 1);
+    }
+
+    #[test]
+    fn standalone_is_charged_only_if_every_client_has_room() {
+        let mut limiter = Limiter::new(LIMITS.parse().unwrap());
+        let start = Instant::now();
+        assert_eq!(limiter.check_standalone_at("com.example.test", &["bedroom"], start), Ok(()));
+
+        // The kitchen has room, but is not charged since the bedroom has none
+        assert_eq!(limiter.check_standalone_at("com.example.test", &["kitchen", "bedroom"], start), Err(2));
+        assert_eq!(limiter.check_standalone_at("com.example.test", &["kitchen"], start), Ok(()));
+    }
+
+    #[test]
+    fn limits_unauthenticated_together() {
+        let limits = "[unauthenticated]\nmessages = { burst = 2, per_second = 1.0 }".parse().unwrap();
+        let mut limiter = Limiter::new(limits);
+        let start = Instant::now();
+        assert_eq!(limiter.check_unauthenticated_at(10, start), Ok(()));
+        assert_eq!(limiter.check_unauthenticated_at(10, start), Ok(()));
+        assert_eq!(limiter.check_unauthenticated_at(10, start), Err(1));
+        assert!(limiter.stats().is_empty());
+    }
+
+    #[test]
+    fn warnings_are_limited_too() {
+        let mut limiter = Limiter::new(Limits::default());
+        let start = Instant::now();
+        assert!(limiter.should_warn_at(Some("com.example.test"), start));
+        assert!(!limiter.should_warn_at(Some("com.example.test"), start + Duration::from_secs(1)));
+        assert!(limiter.should_warn_at(Some("com.example.other"), start));
+        assert!(limiter.should_warn_at(None, start));
+        assert!(limiter.should_warn_at(Some("com.example.test"), start + WARN_INTERVAL));
+    }
+}
@@ -1,82 +1,218 @@
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::net::SocketAddr;
+use std::sync::Mutex as SyncMutex;
 
-use crate::{respond, Response, SkillRegisterMessage};
+use crate::protection::observation_token;
+use crate::{respond, respond_error, respond_vap_error, ConnectedSkill, Response, SkillRegisterMessage};
 
 use coap_lite::{CoapRequest, CoapResponse, ResponseType};
 use futures::{channel::{mpsc, oneshot}, SinkExt};
-use rmp_serde::from_read;
+use rmp_serde::{decode::Error as DecodeError, Deserializer};
 use serde::de::DeserializeOwned;
+use vap_common_skill::structures::{vap_error, VapError};
 
 pub async fn wait_response<F>(
     receiver: oneshot::Receiver<Response>,
     resp: Option<CoapResponse>,
     cb: F
 ) -> Option<CoapResponse> where
-F: FnOnce(&Response) {
+F: FnOnce(&mut Response) {
     match receiver.await {
-        Ok(resp_data) => {
-            cb(&resp_data);
+        Ok(mut resp_data) => {
+            cb(&mut resp_data);
             respond(resp, resp_data.status, resp_data.payload)
         }
         Err(_) => {
-            None
+            // The host dropped the responder without answering
+            respond_error(resp, ResponseType::InternalServerError, vap_error::INTERNAL_ERROR, None)
         }
     }  
 }
 
-pub fn response_not_found(r: Option<CoapResponse>) -> Option<CoapResponse> {
-    respond(r, ResponseType::MethodNotAllowed, vec![])
+pub fn response_not_found(r: Option<CoapResponse>, path: String) -> Option<CoapResponse> {
+    respond_error(r, ResponseType::NotFound, vap_error::NOT_FOUND, Some(path))
 }
 
 pub fn read_payload<T: DeserializeOwned>(payload: &[u8], r: Option<CoapResponse>) -> Result<(T, Option<CoapResponse>), Option<CoapResponse>> {
-    match from_read(Cursor::new(payload)) {
+    let mut de = Deserializer::new(Cursor::new(payload));
+    match serde_path_to_error::deserialize(&mut de) {
         Ok::<T,_>(a) => {
             Ok((a,r))
         }
         Err(e) => {
-            Err(r.map(|mut r|{
-                println!("Found an error while reading payload: {}", &e);
-                let status = match e {
-                    rmp_serde::decode::Error::TypeMismatch(_) => {
-                        coap_lite::ResponseType::RequestEntityIncomplete
-                    }
-
-                    _ => {
-                        coap_lite::ResponseType::BadRequest
-                    }
-                };
-
-                r.set_status(status);
-                r
-            }))
+            println!("Found an error while reading payload: {}", &e);
+            let (status, type_, object) = decode_error(&e.path().to_string(), e.inner());
+            Err(respond_error(r, status, type_, object))
         }
     }
 }
 
+/// Classifies a decoding error following GENERAL.MD, `path` is where inside the
+/// message the error happened (e.g: "nluData[0].intents[1]", "." for the root).
+fn decode_error(path: &str, e: &DecodeError) -> (ResponseType, &'static str, Option<String>) {
+    fn join(path: &str, field: &str) -> String {
+        if path == "." {
+            field.to_string()
+        } else {
+            format!("{}.{}", path, field)
+        }
+    }
+
+    let path_object = if path == "." { None } else { Some(path.to_string()) };
+
+    match e {
+        DecodeError::Syntax(msg) if msg.starts_with("missing field") => {
+            // Serde's message is: missing field `name`
+            let field = msg.split('`').nth(1).unwrap_or_default();
+            (ResponseType::RequestEntityIncomplete, vap_error::MISSING_FIELD, Some(join(path, field)))
+        }
+
+        // A nil where a value was expected, or positional structs that are too short
+        DecodeError::Syntax(msg)
+            if msg.starts_with("invalid type: unit value") || msg.starts_with("invalid length") =>
+        {
+            (ResponseType::RequestEntityIncomplete, vap_error::MISSING_FIELD, path_object)
+        }
+
+        // Valid MsgPack, but not what we expected
+        DecodeError::TypeMismatch(_) | DecodeError::Syntax(_) => {
+            (ResponseType::BadRequest, vap_error::MALFORMED_CONTENT, path_object)
+        }
+
+        // Not even valid MsgPack
+        _ => (ResponseType::BadRequest, vap_error::MALFORMED_CONTENT, None),
+    }
+}
+
+/// Reads the message and sends it to the host if `check` accepts it, otherwise
+/// answers with the error returned by `check`.
 pub async fn handle_msg<T: DeserializeOwned, F, F2>(
     request: CoapRequest<SocketAddr>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    key_check: F2,
+    check: F2,
     cb: F,
 ) -> Option<CoapResponse> where
     F: FnOnce(T) -> SkillRegisterMessage,
-    F2: FnOnce(&T) -> bool{
+    F2: FnOnce(&T) -> Result<(), VapError> {
 
     match read_payload(&request.message.payload, request.response) {
         Ok::<(T,_),_>((p, resp)) => {
-            if  key_check(&p){
-                let (sender, receiver) = oneshot::channel();
-                in_send.send((cb(p), sender)).await.unwrap();
-                wait_response(receiver, resp, |_|{}).await
-            }
-            else {
-                println!("Bad request because key_check");
-                respond(resp, ResponseType::BadRequest, vec![])
+            match check(&p) {
+                Ok(()) => {
+                    let (sender, receiver) = oneshot::channel();
+                    in_send.send((cb(p), sender)).await.unwrap();
+                    wait_response(receiver, resp, |_|{}).await
+                }
+                Err(e) => {
+                    println!("Bad request because of check: {}", e);
+                    respond_vap_error(resp, e)
+                }
             }
         }
         Err(r) => {
             r
         }
     }
-}
\ No newline at end of file
+}
+
+/// Makes sure that the skill is connected and that the message carries its token
+pub fn check_skill(current_skills: &SyncMutex<HashMap<String, ConnectedSkill>>, skill_id: &str, token: Option<&str>) -> Result<(), VapError> {
+    match current_skills.lock().unwrap().get(skill_id) {
+        Some(skill) if Some(skill.token.as_str()) == token => Ok(()),
+        Some(_) => Err(VapError::new(401, vap_error::UNAUTHORIZED).with_object(skill_id)),
+        None => Err(VapError::new(400, vap_error::WRONG_SKILL_ID).with_object(skill_id))
+    }
+}
+
+/// Like `check_skill`, but skills that use OSCORE observe with the token made for it
+pub fn check_observer(current_skills: &SyncMutex<HashMap<String, ConnectedSkill>>, skill_id: &str, token: Option<&str>) -> Result<(), VapError> {
+    let observation = current_skills.lock().unwrap().get(skill_id).and_then(observation_token);
+    if token.is_some() && token == observation.as_deref() {
+        Ok(())
+    } else {
+        check_skill(current_skills, skill_id, token)
+    }
+}
+
+/// The id of the skill with this token, if any
+pub fn skill_with_token(current_skills: &SyncMutex<HashMap<String, ConnectedSkill>>, token: Option<&str>) -> Option<String> {
+    let token = token?;
+    current_skills.lock().unwrap()
+        .iter()
+        .find(|(_, skill)| skill.token == token)
+        .map(|(id, _)| id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coap_lite::Packet;
+    use serde::Serialize;
+    use vap_common_skill::encoding;
+    use vap_common_skill::structures::MsgRegisterIntents;
+
+    /// Reads the payload as registerIntents, returns the error answered
+    fn read_error(payload: &[u8]) -> (ResponseType, VapError) {
+        let resp = CoapResponse::new(&Packet::new());
+        let resp = read_payload::<MsgRegisterIntents>(payload, resp).unwrap_err().unwrap();
+        (*resp.get_status(), encoding::from_slice(&resp.message.payload).unwrap())
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Msg<T> {
+        skill_id: T,
+        nlu_data: Vec<NluData>,
+    }
+
+    #[derive(Serialize)]
+    struct NluData {
+        language: &'static str,
+        intents: Vec<Intent>,
+        entities: Vec<()>,
+    }
+
+    /// An intent without name
+    #[derive(Serialize)]
+    struct Intent {
+        utterances: Vec<()>,
+        slots: Vec<()>,
+    }
+
+    #[test]
+    fn missing_nested_field_is_incomplete() {
+        let msg = Msg {
+            skill_id: "com.example.test",
+            nlu_data: vec![NluData {
+                language: "en-US",
+                intents: vec![Intent { utterances: vec![], slots: vec![] }],
+                entities: vec![],
+            }],
+        };
+
+        let (status, err) = read_error(&encoding::to_vec(&msg).unwrap());
+        assert_eq!(status, ResponseType::RequestEntityIncomplete);
+        assert_eq!(err.code, 408);
+        assert_eq!(err.type_, vap_error::MISSING_FIELD);
+        assert_eq!(err.object.as_deref(), Some("nluData[0].intents[0].name"));
+    }
+
+    #[test]
+    fn wrong_type_is_bad_request() {
+        let msg = Msg { skill_id: 42, nlu_data: vec![] };
+
+        let (status, err) = read_error(&encoding::to_vec(&msg).unwrap());
+        assert_eq!(status, ResponseType::BadRequest);
+        assert_eq!(err.type_, vap_error::MALFORMED_CONTENT);
+        assert_eq!(err.object.as_deref(), Some("skillId"));
+    }
+
+    #[test]
+    fn garbage_is_bad_request() {
+        let (status, err) = read_error(b"\x82\xa7skil");
+        assert_eq!(status, ResponseType::BadRequest);
+        assert_eq!(err.type_, vap_error::MALFORMED_CONTENT);
+        assert_eq!(err.object, None);
+    }
+}
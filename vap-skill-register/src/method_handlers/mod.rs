@@ -1,52 +1,134 @@
 // Handle the incoming CoAP requests
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Instant;
 
-use crate::{respond, Notification, NotificationData,  RequestId, RequestResponse, Response, SkillRegisterMessage, SharedPending};
-use crate::vars::VAP_VERSION;
+use crate::{respond, respond_error, respond_retry, respond_vap_error, status_code, ApprovalRequest, ConnectedSkill, Notification, NotificationData, Pending, Policy, RequestId, RequestResponse, Response, SkillRegisterMessage, SharedPending, State};
+use crate::approvals::Status;
+use crate::limits::Limiter;
+use crate::vars::{APPROVAL_RETRY_SECS, SUPPORTED_VAP_VERSIONS};
 use self::io_helpers::*;
 
-use coap_lite::{CoapRequest, CoapResponse, ResponseType};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, ResponseType};
 use futures::future::{join, join_all};
-use futures::{channel::{mpsc, oneshot}, SinkExt, lock::Mutex};
-use rmp_serde::to_vec_named;
+use futures::{channel::{mpsc, oneshot}, SinkExt};
+use log::warn;
+use rand::{distributions::Alphanumeric, Rng};
+use vap_common_skill::{encoding, oscore};
 use vap_common_skill::structures::*;
+use vap_common_skill::validation::validate_register_intents;
+use vap_common_skill::version::Version;
 
 mod io_helpers;
 
+/// The token the skill put in the request (see `AUTH_TOKEN_OPTION`), if any
+pub fn auth_token(request: &CoapRequest<SocketAddr>) -> Option<String> {
+    let values = request.message.get_option(CoapOption::Unknown(AUTH_TOKEN_OPTION))?;
+    String::from_utf8(values.front()?.clone()).ok()
+}
+
+/// Any message from a skill means it is still alive, returns the id of the
+/// skill with this token (if any)
+pub fn seen(current_skills: &SyncMutex<HashMap<String, ConnectedSkill>>, token: Option<&str>) -> Option<String> {
+    let token = token?;
+    current_skills.lock().unwrap()
+        .iter_mut()
+        .find(|(_, skill)| skill.token == token)
+        .map(|(id, skill)| {
+            skill.last_seen = Instant::now();
+            id.clone()
+        })
+}
+
+/// Charges the message to the skill that sent it, or to the unauthenticated
+/// limits if it had no valid token. Those over their limits are told how long
+/// to wait, and their message goes no further.
+pub fn charge_limits(
+    request: CoapRequest<SocketAddr>,
+    skill_id: Option<String>,
+    state: &State
+) -> Result<CoapRequest<SocketAddr>, Option<CoapResponse>> {
+    let bytes = request.message.payload.len();
+    let mut limiter = state.limiter.lock().unwrap();
+    let charged = match &skill_id {
+        Some(skill_id) => limiter.check_message(skill_id, bytes),
+        None => limiter.check_unauthenticated(bytes),
+    };
+    let should_warn = charged.is_err() && limiter.should_warn(skill_id.as_deref());
+    drop(limiter);
+
+    if let Err(wait) = charged {
+        if should_warn {
+            warn!("{} is over its limits", skill_id.as_deref().unwrap_or("An unauthenticated sender"));
+        }
+        return Err(respond_retry(request.response, ResponseType::TooManyRequests, vap_error::TOO_MANY_REQUESTS, skill_id, wait));
+    }
+
+    Ok(request)
+}
+
 pub async fn on_get(
     request: CoapRequest<SocketAddr>,
+    token: Option<&str>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    current_skills: Arc<std::sync::Mutex<HashMap<String, ()>>>
+    state: &State
 ) -> Option<CoapResponse> {
-    if request.get_path().starts_with("vap/skillRegistry/skills/") {
-        respond(request.response, ResponseType::Content, vec![])
+    let State {current_skills, policy, pending_can_you, pending_requests, ..} = state;
+    const BASE_SKILLS_PATH: &str = "vap/skillRegistry/skills/";
+    const BASE_REQUEST_PATH: &str = "vap/request/";
+
+    let path = request.get_path();
+    if let Some(id) = path.strip_prefix(BASE_SKILLS_PATH) {
+        // Only the skill itself can observe its requests
+        match check_observer(current_skills, id, token) {
+            Ok(()) => respond(request.response, ResponseType::Content, vec![]),
+            Err(e) => respond_vap_error(request.response, e)
+        }
+    }
+
+    else if let Some(request_id) = path.strip_prefix(BASE_REQUEST_PATH) {
+        let skill_id = skill_with_token(current_skills, token);
+        let request_id = request_id.parse::<RequestId>().ok();
+        let is_owner = match (skill_id, request_id) {
+            (Some(skill_id), Some(request_id)) => {
+                is_pending_for(&*pending_requests.lock().await, &request_id, &skill_id) ||
+                is_pending_for(&*pending_can_you.lock().await, &(request_id, skill_id.clone()), &skill_id)
+            }
+            _ => false
+        };
+
+        if is_owner {
+            respond(request.response, ResponseType::Valid, vec![])
+        } else {
+            let object = request_id.map(|id| id.to_string());
+            respond_error(request.response, ResponseType::BadOption, vap_error::WRONG_REQUEST_ID, object)
+        }
     }
 
     else {
-        match request.get_path().as_str() {
+        match path.as_str() {
             "vap/skillRegistry/query" => {
                 handle_msg(
                     request,
                     in_send,
-                    |p: &MsgQuery|current_skills.lock().unwrap().contains_key(&p.skill_id),
+                    |p: &MsgQuery| {
+                        check_skill(current_skills, &p.skill_id, token)?;
+                        policy.check_query(p)
+                    },
                     SkillRegisterMessage::Query
                 ).await
             }
 
             ".well-known/core" => {
-                respond(request.response, ResponseType::Content, b"</vap>;rt=\"vap-skill-registry\"".to_vec())
+                let link = format!("</vap>;rt=\"vap-skill-registry\";vap-versions=\"{}\"", SUPPORTED_VAP_VERSIONS);
+                respond(request.response, ResponseType::Content, link.into_bytes())
             }
 
             _ => {
-                if request.get_path().starts_with("vap/request/") {
-                    // TODO: Make sure only the same skill is asking for it.
-                    respond(request.response, ResponseType::Valid, vec![])
-                } else {
-                    response_not_found(request.response)
-                }
+                response_not_found(request.response, path)
             }
         }
     }
@@ -54,24 +136,37 @@ pub async fn on_get(
 
 pub async fn on_post(
     request: CoapRequest<SocketAddr>,
+    token: Option<&str>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    self_send: &mut mpsc::Sender<(String, Vec<u8>)>,
-    current_skills: &Arc<std::sync::Mutex<HashMap<String, ()>>>,
-    pending_can_you: &Arc<Mutex<HashMap<u64, oneshot::Sender<f32>>>>,
-    pending_requests: &SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>
+    state: &State
 ) -> Option<CoapResponse> {
+    let State {current_skills, connecting, policy, approvals, pending_can_you, pending_requests, limiter, ..} = state;
     match request.get_path().as_str() {
         "vap/skillRegistry/connect" => {
             match read_payload(&request.message.payload, request.response) {
                 Ok::<(MsgConnect,_),_>((p, resp)) => {
-                    // Until there's a way for skill "connections" to be checked we'll
-                    // disable checking whether an skill already exists
-                    //let is_in_dict = current_skills.lock().unwrap().contains_key(&p.id);
-                    let is_in_dict = false;
-                    if !is_in_dict && p.vap_version == VAP_VERSION {
+                    let token = connection_token(&current_skills.lock().unwrap(), &mut connecting.lock().unwrap(), &p);
+                    // Freed once the host answered, the skill is in `current_skills` by then
+                    let _reservation = match &token {
+                        Some((_, true)) => Some(Reservation {connecting, id: p.id.clone()}),
+                        _ => None
+                    };
+                    let version = p.version_range().ok()
+                        .and_then(|r| SUPPORTED_VAP_VERSIONS.negotiate(&r));
+                    // Skills that proved to be already connected were accepted back then
+                    let status = match (approvals, &token) {
+                        (Some(approvals), Some((_, true))) if version.is_some() => {
+                            approvals.lock().unwrap().status(&p.id)
+                        }
+                        _ => Status::Allowed
+                    };
+
+                    if let (Some((token, is_new)), Some(version), Status::Allowed) = (token, version, status) {
                         let (sender, receiver) = oneshot::channel();
                         let skill_id = p.id.clone();
-                        in_send.send((SkillRegisterMessage::Connect(p), sender)).await.unwrap();
+                        let name = p.name.clone();
+                        let intents_digest = p.intents_digest.clone();
+                        in_send.send((SkillRegisterMessage::Connect(p, version), sender)).await.unwrap();
                         
                         wait_response(receiver, resp, |r| {
                             // If it is regarded as "OK"
@@ -83,20 +178,44 @@ pub async fn on_post(
                                 ResponseType::Continue
                                 ].contains(&r.status) {
                                 
-                                // We need to register the skill inside the CoAP server
-                                self_send.try_send((skill_id.clone(), vec![])).unwrap();
-                                current_skills.lock().unwrap().insert(skill_id.clone(),());
+                                let mut skills = current_skills.lock().unwrap();
+                                // Skills coming back with their token keep their intents, if they didn't change
+                                let intents = skills.get(&skill_id)
+                                    .filter(|s| !is_new && intents_digest.is_some() && s.intents_digest == intents_digest)
+                                    .and_then(|s| s.intents.clone());
+
+                                // Skills that sent a valid token already know it
+                                let id_context = new_id_context();
+                                complete_connect_response(r, version, Some(&token).filter(|_| is_new), intents.is_some(), state.heartbeat_secs, &id_context);
+                                let oscore = Arc::new(SyncMutex::new(oscore::registry_context(&token, &id_context)));
+
+                                skills.insert(skill_id.clone(), ConnectedSkill {name, version, token, oscore: Some(oscore), intents, intents_digest, last_seen: Instant::now(), restored: false});
+                                drop(skills);
+                                state.persist();
                             }
                         }).await
                     }
-                    else {
-                        if p.vap_version != VAP_VERSION {
-                            println!("Received a non-compatible version, bad request");
-                        }
-                        else if is_in_dict {
-                            println!("Tried to register a skill already connected, if this a genuine request wait a little");
+                    else if version.is_none() {
+                        println!("Received a non-compatible version ({}), bad request", p.vap_version);
+                        // Let the skill know what we support
+                        respond_error(resp, ResponseType::BadRequest, vap_error::VERSION_INCOMPATIBLE, Some(SUPPORTED_VAP_VERSIONS.to_string()))
+                    }
+                    else if status == Status::Denied {
+                        respond_error(resp, ResponseType::Unauthorized, vap_error::CONNECTION_DENIED, Some(p.id))
+                    }
+                    else if status != Status::Allowed {
+                        if let (Status::Unknown, Some(approvals)) = (status, approvals) {
+                            // The host has to ask the user, the responder is not used
+                            let (sender, _) = oneshot::channel();
+                            let request = ApprovalRequest::new(p.id.clone(), p.name, approvals.clone());
+                            in_send.send((SkillRegisterMessage::Approval(request), sender)).await.unwrap();
                         }
-                        respond(resp, ResponseType::BadRequest, vec![])
+
+                        respond_retry(resp, ResponseType::ServiceUnavailable, vap_error::APPROVAL_PENDING, Some(p.id), APPROVAL_RETRY_SECS)
+                    }
+                    else {
+                        println!("Tried to register a skill already connected without its token");
+                        respond_error(resp, ResponseType::Unauthorized, vap_error::UNAUTHORIZED, Some(p.id))
                     }
                 }
                 Err(r) => {
@@ -106,18 +225,76 @@ pub async fn on_post(
         }
 
         "vap/skillRegistry/registerIntents" => {
-            handle_msg(
-                request,
-                in_send,
-                |p: &MsgRegisterIntents|current_skills.lock().unwrap().contains_key(&p.skill_id),
-                SkillRegisterMessage::RegisterIntents
-            ).await
+            match read_payload(&request.message.payload, request.response) {
+                Ok::<(MsgRegisterIntents,_),_>((p, resp)) => {
+                    let checked = check_skill(current_skills, &p.skill_id, token)
+                        .and_then(|_| validate_register_intents(&p));
+                    if let Err(e) = checked {
+                        println!("Bad request because of check: {}", e);
+                        return respond_vap_error(resp, e);
+                    }
+
+                    let (sender, receiver) = oneshot::channel();
+                    in_send.send((SkillRegisterMessage::RegisterIntents(p.clone()), sender)).await.unwrap();
+                    wait_response(receiver, resp, |r| {
+                        if r.status == ResponseType::Created {
+                            if let Some(nlu) = &state.nlu {
+                                nlu.lock().unwrap().add(&p);
+                            }
+                            // Kept so that the skill doesn't need to send them again
+                            if let Some(skill) = current_skills.lock().unwrap().get_mut(&p.skill_id) {
+                                skill.intents = Some(p);
+                            }
+                            state.persist();
+                        }
+                    }).await
+                }
+                Err(r) => {
+                    r
+                }
+            }
+        }
+
+        "vap/skillRegistry/heartbeat" => {
+            // Just by arriving the skill is regarded as alive
+            match read_payload(&request.message.payload, request.response) {
+                Ok::<(MsgHeartbeat,_),_>((p, resp)) => {
+                    match check_skill(current_skills, &p.skill_id, token) {
+                        Ok(()) => respond(resp, ResponseType::Changed, vec![]),
+                        Err(e) => respond_vap_error(resp, e)
+                    }
+                }
+                Err(r) => {
+                    r
+                }
+            }
         }
 
         "vap/skillRegistry/notification" => {
             
             match read_payload(&request.message.payload, request.response) {
                 Ok::<(MsgNotification,_),_>((msg, resp)) => {
+                    if let Err(e) = check_skill(current_skills, &msg.skill_id, token) {
+                        return respond_vap_error(resp, e);
+                    }
+
+                    // Nothing is sent to the host if any part is not allowed
+                    if let Err(e) = check_notify(policy, &msg, pending_requests).await {
+                        return respond_vap_error(resp, e);
+                    }
+
+                    let standalone = {
+                        let mut limiter = limiter.lock().unwrap();
+                        check_standalone(&mut limiter, &msg)
+                            .map_err(|wait| (wait, limiter.should_warn(Some(&msg.skill_id))))
+                    };
+                    if let Err((wait, should_warn)) = standalone {
+                        if should_warn {
+                            warn!("Skill {} sent too many notifications", msg.skill_id);
+                        }
+                        return respond_retry(resp, ResponseType::TooManyRequests, vap_error::TOO_MANY_REQUESTS, Some(msg.skill_id), wait);
+                    }
+
                     let mut standalone = vec![];
                     let mut resolutions = vec![];
 
@@ -133,19 +310,20 @@ pub async fn on_post(
                             msg_notification::Data::CanYouAnswer{request_id, confidence} => {
                                 fn can_you_answer_done(response: coap_lite::ResponseType, id: RequestId) -> RequestResolution {
                                     RequestResolution::Done(msg_notification_response::Data::CanYouAnswer {
-                                        code: response as u16,
+                                        code: status_code(response),
                                         request_id: id
                                     })
                                 }
 
-                                let resol= match pending_can_you.lock().await.remove(&request_id) {
-                                    Some(pending_sender) => {
-                                        pending_sender.send(confidence).unwrap();
+                                let pending = take_pending(&mut *pending_can_you.lock().await, &(request_id, skill_id.clone()), &skill_id);
+                                // Nobody waits for it if it timed out or was cancelled
+                                let resol = match pending.map(|p| p.sender.send(confidence)) {
+                                    Some(Ok(())) => {
                                         can_you_answer_done(coap_lite::ResponseType::Valid, request_id)
-                                        
                                     }
-                                    None => {
-                                        can_you_answer_done(coap_lite::ResponseType::BadRequest, request_id)
+                                    _ => {
+                                        // Doesn't exist, belongs to another skill or was given up
+                                        can_you_answer_done(coap_lite::ResponseType::BadOption, request_id)
                                     }
                                 };
 
@@ -154,20 +332,20 @@ pub async fn on_post(
                             msg_notification::Data::Requested {request_id, capabilities} => {
                                 fn requested_done(response: coap_lite::ResponseType, id: RequestId) -> RequestResolution {
                                     RequestResolution::Done(msg_notification_response::Data::Requested {
-                                        code: response as u16,
+                                        code: status_code(response),
                                         request_id: id
                                     })
                                 }
 
-                                let resol = match pending_requests.lock().await.remove(&request_id) {
-                                    Some(pending_sender) => {
-
-                                        let (sender, receiver) = oneshot::channel();
-                                        pending_sender.send((capabilities.clone(), sender)).unwrap();
+                                let pending = take_pending(&mut *pending_requests.lock().await, &request_id, &skill_id);
+                                let (sender, receiver) = oneshot::channel();
+                                // Nobody waits for it if it timed out or was cancelled
+                                let resol = match pending.map(|p| p.sender.send((capabilities.clone(), sender))) {
+                                    Some(Ok(())) => {
                                         RequestResolution::InProcess((request_id, receiver))
                                     }
-                                    None => {
-                                        requested_done(coap_lite::ResponseType::BadRequest, request_id)
+                                    _ => {
+                                        requested_done(coap_lite::ResponseType::BadOption, request_id)
                                     }
                                 };
 
@@ -220,7 +398,7 @@ pub async fn on_post(
                         other_res.extend(res);
                             
                         resp.map(|mut r| {
-                            let payload = to_vec_named(&MsgNotificationResponse {
+                            let payload = encoding::to_vec(&MsgNotificationResponse {
                                 data: other_res
                             }).unwrap();
                             
@@ -236,29 +414,55 @@ pub async fn on_post(
             }
         }
 
-        _ => response_not_found(request.response)
+        _ => {
+            let path = request.get_path();
+            response_not_found(request.response, path)
+        }
     }                    
 }
 
 pub async fn on_delete(
     request: CoapRequest<SocketAddr>,
+    token: Option<&str>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    current_skills: Arc<SyncMutex<HashMap<String, ()>>>,
+    state: &State,
 ) -> Option<CoapResponse> {
+    let current_skills = &state.current_skills;
     let path = request.get_path();
     const BASE_SKILLS_PATH: &str = "vap/skillRegistry/skills/";
-    if path.starts_with("vap/skillRegistry/skills/") {
-        let id = &path[BASE_SKILLS_PATH.len()..];
-
+    if let Some(id) = path.strip_prefix(BASE_SKILLS_PATH) {
         match read_payload(&request.message.payload, request.response) {
             Ok::<(MsgSkillClose, _), _>((p, resp)) => {
-                if current_skills.lock().unwrap().contains_key(id) {
-                    let (sender, receiver) = oneshot::channel();
-                    in_send.send((SkillRegisterMessage::Close(p), sender)).await.unwrap();
-                    wait_response(receiver, resp, |_|{}).await
+                let checked = if p.skill_id == id {
+                    check_skill(current_skills, id, token)
+                } else {
+                    Err(VapError::new(400, vap_error::WRONG_SKILL_ID).with_object(&p.skill_id))
+                };
+
+                if let Err(e) = checked {
+                    respond_vap_error(resp, e)
                 }
                 else {
-                    respond(resp, ResponseType::BadRequest, vec![])
+                    let (sender, receiver) = oneshot::channel();
+                    in_send.send((SkillRegisterMessage::Close(p), sender)).await.unwrap();
+                    let mut closed = false;
+                    let resp = wait_response(receiver, resp, |r| {
+                        if r.status == ResponseType::Deleted {
+                            // The id is free again
+                            current_skills.lock().unwrap().remove(id);
+                            if let Some(nlu) = &state.nlu {
+                                nlu.lock().unwrap().remove(id);
+                            }
+                            state.persist();
+                            closed = true;
+                        }
+                    }).await;
+
+                    if closed {
+                        // It won't answer anything anymore
+                        state.drop_pending(&[id.to_string()]).await;
+                    }
+                    resp
                 }
             }
             Err(r) => {
@@ -267,6 +471,306 @@ pub async fn on_delete(
         }
     }
     else {
-        response_not_found(request.response)
+        response_not_found(request.response, path)
     }
-}
\ No newline at end of file
+}
+
+/// Tells the skill which version was agreed on, its token (if it is a new
+/// one), whether we still have its intents, how often it has to send
+/// heartbeats and the ID Context of its OSCORE security context, inside the
+/// host's answer to connect
+fn complete_connect_response(
+    r: &mut Response,
+    version: Version,
+    token: Option<&String>,
+    intents_known: bool,
+    heartbeat_secs: Option<u32>,
+    id_context: &str
+) {
+    if let Ok(mut msg) = encoding::from_slice::<MsgConnectResponse>(&r.payload) {
+        msg.vap_version = Some(version);
+        msg.unique_authentication_token = token.cloned();
+        msg.intents_known = intents_known;
+        msg.oscore_id_context = Some(id_context.to_string());
+        msg.heartbeat_secs = heartbeat_secs;
+        r.payload = encoding::to_vec(&msg).unwrap();
+    }
+}
+
+/// Decides the token of a connecting skill and whether it is a new one. A skill
+/// that is already connected can only connect again with its token (e.g: after
+/// a crash), otherwise someone else is trying to take its id and `None` is returned.
+/// New ids are reserved in `connecting`, so that two skills can't get them at once.
+fn connection_token(
+    current_skills: &HashMap<String, ConnectedSkill>,
+    connecting: &mut HashSet<String>,
+    msg: &MsgConnect
+) -> Option<(String, bool)> {
+    match current_skills.get(&msg.id) {
+        Some(skill) => {
+            if msg.unique_authentication_token.as_ref() == Some(&skill.token) {
+                Some((skill.token.clone(), false))
+            }
+            else {
+                None
+            }
+        }
+        None if connecting.insert(msg.id.clone()) => Some((new_token(), true)),
+        None => None
+    }
+}
+
+/// An id reserved by `connection_token`, freed when dropped
+struct Reservation<'a> {
+    connecting: &'a SyncMutex<HashSet<String>>,
+    id: String
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.connecting.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Removes a pending request, but only if it was sent to `skill_id`
+fn take_pending<K: Eq + Hash, D>(
+    pending: &mut HashMap<K, Pending<D>>,
+    key: &K,
+    skill_id: &str
+) -> Option<Pending<D>> {
+    if is_pending_for(pending, key, skill_id) {
+        pending.remove(key)
+    }
+    else {
+        None
+    }
+}
+
+fn is_pending_for<K: Eq + Hash, D>(
+    pending: &HashMap<K, Pending<D>>,
+    key: &K,
+    skill_id: &str
+) -> bool {
+    matches!(pending.get(key), Some(p) if p.skill_id == skill_id)
+}
+
+/// Makes sure the skill can send every capability in the notification, answers
+/// go to the client that made the request.
+async fn check_notify<D>(
+    policy: &Policy,
+    msg: &MsgNotification,
+    pending_requests: &SharedPending<RequestId, D>
+) -> Result<(), VapError> {
+    for d in &msg.data {
+        match d {
+            msg_notification::Data::StandAlone{client_id, capabilities} => {
+                policy.check_notify(&msg.skill_id, client_id, capabilities)?;
+            }
+            msg_notification::Data::Requested{request_id, capabilities} => {
+                let client_id = pending_requests.lock().await.get(request_id)
+                    .map(|p| p.client_id.clone());
+
+                // Unknown requests are answered later on with their own code
+                if let Some(client_id) = client_id {
+                    policy.check_notify(&msg.skill_id, &client_id, capabilities)?;
+                }
+            }
+            msg_notification::Data::CanYouAnswer{..} => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Charges every standalone notification to the skill, one for each client
+fn check_standalone(limiter: &mut Limiter, msg: &MsgNotification) -> Result<(), u32> {
+    let client_ids: Vec<&str> = msg.data.iter()
+        .filter_map(|d| match d {
+            msg_notification::Data::StandAlone{client_id, ..} => Some(client_id.as_str()),
+            _ => None
+        })
+        .collect();
+
+    limiter.check_standalone(&msg.skill_id, &client_ids)
+}
+
+fn new_token() -> String {
+    const TOKEN_LEN: usize = 32;
+    random_alphanumeric(TOKEN_LEN)
+}
+
+/// The ID Context of the OSCORE security context of a new connection, it must
+/// never repeat with the same token
+fn new_id_context() -> String {
+    const ID_CONTEXT_LEN: usize = 12;
+    random_alphanumeric(ID_CONTEXT_LEN)
+}
+
+fn random_alphanumeric(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{connected_skill, request, TOKEN};
+    use crate::{SkillRegister, SkillRegisterStream, VAP_VERSION};
+    use coap_lite::RequestType as Method;
+    use std::time::Duration;
+
+    const ID: &str = "com.example.test";
+
+    /// A registry where `ID` is connected with `TOKEN`, its intents were
+    /// registered on a connect with "digest"
+    fn connected() -> (SkillRegister, SkillRegisterStream) {
+        let (reg, stream, _) = SkillRegister::new(0).unwrap();
+        let mut skill = connected_skill("Test");
+        skill.intents = Some(MsgRegisterIntents {skill_id: ID.into(), nlu_data: vec![]});
+        skill.intents_digest = Some("digest".into());
+        reg.state.current_skills.lock().unwrap().insert(ID.into(), skill);
+        (reg, stream)
+    }
+
+    /// Makes a request pending for another skill
+    async fn pending_for_other(state: &State, request_id: RequestId) {
+        let (sender, _) = oneshot::channel();
+        state.pending_requests.lock().await.insert(request_id, Pending {
+            skill_id: "com.example.other".into(),
+            client_id: "client".into(),
+            sender,
+        });
+    }
+
+    fn vap_error(resp: Option<CoapResponse>) -> (ResponseType, VapError) {
+        let resp = resp.unwrap();
+        (*resp.get_status(), encoding::from_slice(&resp.message.payload).unwrap())
+    }
+
+    fn connect() -> CoapRequest<SocketAddr> {
+        connect_with(None, None)
+    }
+
+    fn connect_with(token: Option<&str>, intents_digest: Option<&str>) -> CoapRequest<SocketAddr> {
+        request(Method::Post, "vap/skillRegistry/connect", &MsgConnect {
+            id: ID.into(),
+            name: "Test".into(),
+            vap_version: VAP_VERSION.to_string(),
+            min_vap_version: None,
+            unique_authentication_token: token.map(String::from),
+            intents_digest: intents_digest.map(String::from),
+        })
+    }
+
+    fn connect_response() -> Response {
+        Response {
+            status: ResponseType::Created,
+            payload: encoding::to_vec(&MsgConnectResponse {
+                langs: vec![],
+                vap_version: None,
+                unique_authentication_token: None,
+                oscore_id_context: None,
+                intents_known: false,
+                heartbeat_secs: None,
+            }).unwrap(),
+        }
+    }
+
+    fn status(resp: Option<CoapResponse>) -> ResponseType {
+        *resp.unwrap().get_status()
+    }
+
+    #[tokio::test]
+    async fn new_ids_are_reserved_while_connecting() {
+        let (reg, mut stream, _) = SkillRegister::new(0).unwrap();
+        let (mut first, mut second) = (reg.in_send.clone(), reg.in_send.clone());
+
+        // Only one of them reaches the host, which answers after both arrived
+        let host = async {
+            let (msg, responder) = stream.recv().await.unwrap();
+            assert!(matches!(msg, SkillRegisterMessage::Connect(..)));
+            responder.send(connect_response()).ok().unwrap();
+        };
+        let both = join(
+            join(
+                on_post(connect(), None, &mut first, &reg.state),
+                on_post(connect(), None, &mut second, &reg.state),
+            ),
+            host
+        );
+        let ((a, b), ()) = tokio::time::timeout(Duration::from_secs(5), both).await
+            .expect("Both connects were sent to the host");
+
+        assert_eq!(status(a), ResponseType::Created);
+        assert_eq!(status(b), ResponseType::Unauthorized);
+        assert!(reg.state.current_skills.lock().unwrap().contains_key(ID));
+        assert!(reg.state.connecting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn intents_are_known_only_with_the_same_digest() {
+        let (reg, mut stream) = connected();
+        let mut in_send = reg.in_send.clone();
+
+        for (digest, known) in [(Some("digest"), true), (Some("changed"), false), (Some("changed"), false), (None, false)] {
+            let host = async {
+                let (_, responder) = stream.recv().await.unwrap();
+                responder.send(connect_response()).ok().unwrap();
+            };
+            let (resp, ()) = join(on_post(connect_with(Some(TOKEN), digest), None, &mut in_send, &reg.state), host).await;
+            let resp: MsgConnectResponse = encoding::from_slice(&resp.unwrap().message.payload).unwrap();
+            assert_eq!(resp.intents_known, known, "{:?}", digest);
+        }
+    }
+
+    #[test]
+    fn reads_the_token_option() {
+        let mut request = request(Method::Get, "vap/request/1", &());
+        assert_eq!(auth_token(&request), None);
+        request.message.add_option(CoapOption::Unknown(AUTH_TOKEN_OPTION), TOKEN.into());
+        assert_eq!(auth_token(&request).as_deref(), Some(TOKEN));
+    }
+
+    #[tokio::test]
+    async fn messages_without_the_token_are_unauthorized() {
+        let (reg, _stream) = connected();
+        let mut in_send = reg.in_send.clone();
+        let observe = || request(Method::Get, &format!("vap/skillRegistry/skills/{}", ID), &());
+
+        for token in [None, Some("other")] {
+            let (code, err) = vap_error(on_get(observe(), token, &mut in_send, &reg.state).await);
+            assert_eq!(code, ResponseType::Unauthorized);
+            assert_eq!(err.type_, vap_error::UNAUTHORIZED);
+            assert_eq!(err.object.as_deref(), Some(ID));
+        }
+
+        let resp = on_get(observe(), Some(TOKEN), &mut in_send, &reg.state).await;
+        assert_eq!(status(resp), ResponseType::Content);
+    }
+
+    #[tokio::test]
+    async fn requests_of_other_skills_are_bad_options() {
+        let (reg, _stream) = connected();
+        let mut in_send = reg.in_send.clone();
+        pending_for_other(&reg.state, 7).await;
+
+        let resp = on_get(request(Method::Get, "vap/request/7", &()), Some(TOKEN), &mut in_send, &reg.state).await;
+        let (code, err) = vap_error(resp);
+        assert_eq!(code, ResponseType::BadOption);
+        assert_eq!(err.type_, vap_error::WRONG_REQUEST_ID);
+        assert_eq!(err.object.as_deref(), Some("7"));
+
+        // Answering it is rejected too, and the request is still pending for its skill
+        let answer = request(Method::Post, "vap/skillRegistry/notification", &MsgNotification {
+            skill_id: ID.into(),
+            data: vec![msg_notification::Data::Requested {request_id: 7, capabilities: vec![]}],
+        });
+        let resp = on_post(answer, Some(TOKEN), &mut in_send, &reg.state).await.unwrap();
+        let resp: MsgNotificationResponse = encoding::from_slice(&resp.message.payload).unwrap();
+        assert!(matches!(resp.data[..], [msg_notification_response::Data::Requested {code: 402, request_id: 7}]));
+        assert!(reg.state.pending_requests.lock().await.contains_key(&7));
+    }
+}
@@ -0,0 +1,395 @@
+//! A simple NLU for assistants without one: matches what the user said against
+//! the utterances registered by skills. Placeholders (`{slot}`) match the values
+//! of their entity (and their synonyms), or any text if the entity is not strict.
+//!
+//! Slots are resolved to the value of their entity here too, whichever NLU
+//! produced them, see `resolve_slots`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use unic_langid::LanguageIdentifier;
+use vap_common_skill::structures::msg_register_intents::{NluData, NluDataEntity};
+use vap_common_skill::structures::msg_skill_request::{RequestData, RequestDataKind, RequestSlot};
+use vap_common_skill::structures::{Language, MsgRegisterIntents};
+
+pub(crate) type SharedNlu = Arc<SyncMutex<TemplateNlu>>;
+/// Slots found in the input: name, first word and number of words
+type Spans = Vec<(String, usize, usize)>;
+
+/// What the user said, as understood by the registry
+#[derive(Clone, Debug)]
+pub struct Understood {
+    /// The skill whose intent matched
+    pub skill_id: String,
+    /// Ready to be sent to the skill, see `SkillRegisterOut::activate_skill`
+    pub request: RequestData,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Entity {
+    strict: bool,
+    /// Values and synonyms, as lowercase words
+    phrases: Vec<Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Words(Vec<String>),
+    Slot { name: String, entity: Entity },
+}
+
+#[derive(Debug)]
+struct Template {
+    skill_id: String,
+    intent: String,
+    /// Every slot of the intent, even those not in this utterance
+    slots: Vec<String>,
+    parts: Vec<Part>,
+}
+
+/// The utterances of every skill, by language (canonical, so that "EN-us" and
+/// "en-US" are the same)
+#[derive(Debug, Default)]
+pub(crate) struct TemplateNlu {
+    templates: HashMap<LanguageIdentifier, Vec<Template>>,
+}
+
+impl TemplateNlu {
+    /// Indexes the intents of a skill, replacing those it had before
+    pub(crate) fn add(&mut self, msg: &MsgRegisterIntents) {
+        self.remove(&msg.skill_id);
+        for data in &msg.nlu_data {
+            // Registering intents in invalid languages is refused beforehand
+            let lang = match canonical(&data.language) {
+                Some(lang) => lang,
+                None => continue,
+            };
+            let templates = self.templates.entry(lang).or_default();
+            templates.extend(templates_of(&msg.skill_id, data));
+        }
+    }
+
+    pub(crate) fn remove(&mut self, skill_id: &str) {
+        for templates in self.templates.values_mut() {
+            templates.retain(|t| t.skill_id != skill_id);
+        }
+    }
+
+    /// The intent that best matches the input, if any. Utterances with more
+    /// words in common with the input win.
+    pub(crate) fn understand(&self, input: &str, locale: &str) -> Option<Understood> {
+        let lang = locale.parse::<LanguageIdentifier>().ok()?;
+        let original = split_words(input);
+        let lowercase: Vec<String> = original.iter().map(|w| w.to_lowercase()).collect();
+
+        let (_, template, found) = self
+            .templates
+            .get(&lang)?
+            .iter()
+            .filter_map(|t| {
+                let (score, found) = match_parts(&t.parts, &lowercase, 0)?;
+                Some((score, t, found))
+            })
+            .filter(|(score, _, _)| *score > 0)
+            .fold(None, |best: Option<(usize, &Template, _)>, candidate| match best {
+                Some(best) if best.0 >= candidate.0 => Some(best),
+                _ => Some(candidate),
+            })?;
+
+        let slots = template
+            .slots
+            .iter()
+            .map(|name| RequestSlot {
+                name: name.clone(),
+                value: found
+                    .iter()
+                    .find(|(n, _, _)| n == name)
+                    .map(|(_, start, len)| original[*start..start + len].join(" ")),
+                // Done by `activate_skill`, as with any other request
+                resolved: None,
+            })
+            .collect();
+
+        Some(Understood {
+            skill_id: template.skill_id.clone(),
+            request: RequestData {
+                type_: RequestDataKind::Intent,
+                intent: template.intent.clone(),
+                locale: locale.to_string(),
+                input: Some(input.to_string()),
+                slots,
+                capabilities: vec![],
+                event: None,
+            },
+        })
+    }
+}
+
+/// Fills `resolved` in the slots of a request, with the intents of the skill
+/// it goes to. Returns the slot whose value its (strict) entity doesn't accept.
+pub(crate) fn resolve_slots(intents: &MsgRegisterIntents, request: &mut RequestData) -> Result<(), String> {
+    let lang = request.locale.parse::<LanguageIdentifier>().ok();
+    let found = intents
+        .nlu_data
+        .iter()
+        .find(|d| lang.is_some() && canonical(&d.language) == lang)
+        .and_then(|data| Some((data, data.intents.iter().find(|i| i.name == request.intent)?)));
+    let (data, intent) = match found {
+        Some(found) => found,
+        None => return unresolvable(intents, request),
+    };
+
+    for slot in &mut request.slots {
+        let entity = intent
+            .slots
+            .iter()
+            .find(|s| s.name == slot.name)
+            .and_then(|s| data.entities.iter().find(|e| e.name == s.entity));
+        let (entity, value) = match (entity, &slot.value) {
+            (Some(entity), Some(value)) => (entity, lowercase_words(value)),
+            _ => continue,
+        };
+
+        slot.resolved = entity
+            .data
+            .iter()
+            .find(|d| {
+                std::iter::once(&d.value)
+                    .chain(&d.synonyms)
+                    .any(|p| lowercase_words(p) == value)
+            })
+            .map(|d| d.value.clone());
+        if slot.resolved.is_none() && entity.strict {
+            return Err(slot.name.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// The locale or the intent of the request are not the skill's, so values
+/// can't be checked: only fine if none of its entities is strict
+fn unresolvable(intents: &MsgRegisterIntents, request: &RequestData) -> Result<(), String> {
+    let strict = intents.nlu_data.iter().flat_map(|d| &d.entities).any(|e| e.strict);
+    match request.slots.iter().find(|s| s.value.is_some()) {
+        Some(slot) if strict => Err(slot.name.clone()),
+        _ => Ok(()),
+    }
+}
+
+fn canonical(lang: &Language) -> Option<LanguageIdentifier> {
+    LanguageIdentifier::try_from(lang).ok()
+}
+
+fn templates_of(skill_id: &str, data: &NluData) -> Vec<Template> {
+    let entities: HashMap<&str, Entity> = data
+        .entities
+        .iter()
+        .map(|e| (e.name.as_str(), entity(e)))
+        .collect();
+
+    data.intents
+        .iter()
+        .flat_map(|intent| {
+            let slot_entities: HashMap<&str, &Entity> = intent
+                .slots
+                .iter()
+                .filter_map(|s| Some((s.name.as_str(), entities.get(s.entity.as_str())?)))
+                .collect();
+
+            intent.utterances.iter().map(move |utterance| Template {
+                skill_id: skill_id.to_string(),
+                intent: intent.name.clone(),
+                slots: intent.slots.iter().map(|s| s.name.clone()).collect(),
+                parts: parse(&utterance.text, &slot_entities),
+            })
+        })
+        .collect()
+}
+
+fn entity(e: &NluDataEntity) -> Entity {
+    Entity {
+        strict: e.strict,
+        phrases: e
+            .data
+            .iter()
+            .flat_map(|d| std::iter::once(&d.value).chain(&d.synonyms))
+            .map(|p| lowercase_words(p))
+            .collect(),
+    }
+}
+
+/// Turns "turn on the {device}" into its words and slots
+fn parse(text: &str, entities: &HashMap<&str, &Entity>) -> Vec<Part> {
+    fn push_words(parts: &mut Vec<Part>, text: &str) {
+        let words = lowercase_words(text);
+        if !words.is_empty() {
+            parts.push(Part::Words(words));
+        }
+    }
+
+    let mut parts = vec![];
+    let mut rest = text;
+    while let Some((before, after)) = rest.split_once('{') {
+        push_words(&mut parts, before);
+        let (name, after) = after.split_once('}').unwrap_or((after, ""));
+        let name = name.trim();
+        parts.push(Part::Slot {
+            name: name.to_string(),
+            entity: entities.get(name).map(|e| (*e).clone()).unwrap_or_default(),
+        });
+        rest = after;
+    }
+    push_words(&mut parts, rest);
+
+    parts
+}
+
+fn split_words(text: &str) -> Vec<&str> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+fn lowercase_words(text: &str) -> Vec<String> {
+    split_words(text).iter().map(|w| w.to_lowercase()).collect()
+}
+
+/// Matches the whole input (from `offset` on) against the parts. The score is
+/// how many words were known (not taken by free text).
+fn match_parts(parts: &[Part], input: &[String], offset: usize) -> Option<(usize, Spans)> {
+    match parts.split_first() {
+        None if offset == input.len() => Some((0, vec![])),
+        None => None,
+        Some((Part::Words(words), rest)) => {
+            if input[offset..].starts_with(words) {
+                let (score, slots) = match_parts(rest, input, offset + words.len())?;
+                Some((score + words.len(), slots))
+            } else {
+                None
+            }
+        }
+        Some((Part::Slot { name, entity }, rest)) => {
+            let mut best: Option<(usize, Spans)> = None;
+            for len in 1..=input.len() - offset {
+                let span = &input[offset..offset + len];
+                let known = entity.phrases.iter().any(|p| p == span);
+                if !known && entity.strict {
+                    continue;
+                }
+
+                if let Some((score, mut slots)) = match_parts(rest, input, offset + len) {
+                    let score = score + if known { len } else { 0 };
+                    if !matches!(&best, Some((b, _)) if *b >= score) {
+                        slots.insert(0, (name.clone(), offset, len));
+                        best = Some((score, slots));
+                    }
+                }
+            }
+
+            best
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vap_common_skill::structures::msg_register_intents::*;
+
+    fn msg() -> MsgRegisterIntents {
+        MsgRegisterIntents {
+            skill_id: "com.example.lights".into(),
+            nlu_data: vec![NluData {
+                language: "en-US".parse().unwrap(),
+                intents: vec![NluDataIntent {
+                    name: "turn_on".into(),
+                    utterances: vec![
+                        NluDataIntentUtterance {
+                            text: "turn on the {room} lights".into(),
+                        },
+                        NluDataIntentUtterance {
+                            text: "lights on".into(),
+                        },
+                    ],
+                    slots: vec![NluDataSlot {
+                        name: "room".into(),
+                        entity: "room".into(),
+                        required: false,
+                        prompt: None,
+                        reprompt: None,
+                    }],
+                }],
+                entities: vec![NluDataEntity {
+                    name: "room".into(),
+                    strict: true,
+                    data: vec![NluDataEntityData {
+                        value: "living room".into(),
+                        synonyms: vec!["lounge".into()],
+                    }],
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn matches_templates_and_fills_slots() {
+        let mut nlu = TemplateNlu::default();
+        nlu.add(&msg());
+
+        let understood = nlu.understand("Turn on the Living Room lights!", "en-US").unwrap();
+        assert_eq!(understood.skill_id, "com.example.lights");
+        assert_eq!(understood.request.intent, "turn_on");
+        assert_eq!(understood.request.slots[0].value.as_deref(), Some("Living Room"));
+
+        let understood = nlu.understand("lights on", "en-US").unwrap();
+        assert_eq!(understood.request.slots[0].value, None);
+
+        // The entity is strict, and only English was registered
+        assert!(nlu.understand("turn on the garage lights", "en-US").is_none());
+        assert!(nlu.understand("turn on the lounge lights", "es-ES").is_none());
+
+        nlu.remove("com.example.lights");
+        assert!(nlu.understand("lights on", "en-US").is_none());
+    }
+
+    #[test]
+    fn resolves_synonyms_and_rejects_strict() {
+        let mut nlu = TemplateNlu::default();
+        nlu.add(&msg());
+        let mut request = nlu.understand("turn on the Lounge lights", "en-US").unwrap().request;
+        resolve_slots(&msg(), &mut request).unwrap();
+        assert_eq!(request.slots[0].value.as_deref(), Some("Lounge"));
+        assert_eq!(request.slots[0].resolved.as_deref(), Some("living room"));
+
+        request.slots[0].value = Some("garage".into());
+        assert_eq!(resolve_slots(&msg(), &mut request), Err("room".to_string()));
+
+        // Values can't be checked in other languages or intents
+        for (locale, intent) in [("es-ES", "turn_on"), ("not a locale", "turn_on"), ("en-US", "turn_off")] {
+            request.locale = locale.into();
+            request.intent = intent.into();
+            request.slots[0].value = Some("lounge".into());
+            assert_eq!(resolve_slots(&msg(), &mut request), Err("room".to_string()));
+        }
+    }
+
+    #[test]
+    fn languages_are_compared_canonically() {
+        let mut msg = msg();
+        msg.nlu_data[0].language = Language {
+            language: "EN".into(),
+            country: Some("us".into()),
+            script: None,
+            variants: vec![],
+        };
+        let mut nlu = TemplateNlu::default();
+        nlu.add(&msg);
+
+        let mut request = nlu.understand("turn on the lounge lights", "en-US").unwrap().request;
+        resolve_slots(&msg, &mut request).unwrap();
+        assert_eq!(request.slots[0].resolved.as_deref(), Some("living room"));
+    }
+}
@@ -0,0 +1,229 @@
+//! Which capabilities and clients each skill is allowed to use. Written by
+//! whoever deploys the registry, as TOML:
+//!
+//! ```toml
+//! # Skills not listed below can't query nor notify anything
+//! [default]
+//! receive = []
+//!
+//! [skills."com.example.bank"]
+//! query = [{ clients = ["*"], capabilities = ["text"] }]
+//! notify = [{ clients = ["*"], capabilities = ["text", "voice"] }]
+//! receive = ["voice"]
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use vap_common_skill::structures::{vap_error, MsgQuery, PlainCapability, VapError};
+
+use crate::Error;
+
+/// Matches any client or capability
+const ANY: &str = "*";
+
+/// Code of errors for skills without permission (Unauthorized)
+const UNAUTHORIZED: u16 = 401;
+
+/// Allows some capabilities for some clients
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    /// Ids of the clients ("vap.SYSTEM" for the system itself), "*" for any
+    pub clients: Vec<String>,
+    /// Names of the capabilities, "*" for any
+    pub capabilities: Vec<String>,
+}
+
+impl Rule {
+    /// A rule that allows everything
+    pub fn any() -> Self {
+        Rule {
+            clients: vec![ANY.into()],
+            capabilities: vec![ANY.into()],
+        }
+    }
+
+    fn allows(&self, client_id: &str, capability: &str) -> bool {
+        matches(&self.clients, client_id) && matches(&self.capabilities, capability)
+    }
+}
+
+/// What a skill is allowed to do, anything not allowed is denied
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SkillPolicy {
+    /// Capabilities the skill can ask for
+    #[serde(default)]
+    pub query: Vec<Rule>,
+    /// Capabilities the skill can send, both standalone and as answers
+    #[serde(default)]
+    pub notify: Vec<Rule>,
+    /// Capabilities that can reach the skill along with a request, the rest
+    /// are removed before sending it
+    #[serde(default)]
+    pub receive: Vec<String>,
+}
+
+impl SkillPolicy {
+    /// A policy that allows everything
+    pub fn allow_all() -> Self {
+        SkillPolicy {
+            query: vec![Rule::any()],
+            notify: vec![Rule::any()],
+            receive: vec![ANY.into()],
+        }
+    }
+}
+
+/// The permissions of every skill
+#[derive(Clone, Debug, Deserialize)]
+pub struct Policy {
+    /// Used for skills not present in `skills`
+    #[serde(default = "SkillPolicy::allow_all")]
+    pub default: SkillPolicy,
+    #[serde(default)]
+    pub skills: HashMap<String, SkillPolicy>,
+}
+
+/// Allows everything to every skill
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            default: SkillPolicy::allow_all(),
+            skills: HashMap::new(),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+impl Policy {
+    /// Reads a policy from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    fn skill(&self, skill_id: &str) -> &SkillPolicy {
+        self.skills.get(skill_id).unwrap_or(&self.default)
+    }
+
+    /// Makes sure the skill can ask for every capability in the query
+    pub fn check_query(&self, msg: &MsgQuery) -> Result<(), VapError> {
+        let rules = &self.skill(&msg.skill_id).query;
+        for data in &msg.data {
+            check_rules(rules, &data.client_id, &data.capabilities)?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure the skill can send those capabilities to the client
+    pub fn check_notify(
+        &self,
+        skill_id: &str,
+        client_id: &str,
+        capabilities: &[PlainCapability],
+    ) -> Result<(), VapError> {
+        check_rules(&self.skill(skill_id).notify, client_id, capabilities)
+    }
+
+    /// Removes the capabilities the skill is not allowed to receive
+    pub fn filter_received(
+        &self,
+        skill_id: &str,
+        capabilities: Vec<PlainCapability>,
+    ) -> Vec<PlainCapability> {
+        let allowed = &self.skill(skill_id).receive;
+        capabilities
+            .into_iter()
+            .filter(|c| matches(allowed, &c.name))
+            .collect()
+    }
+}
+
+fn check_rules(
+    rules: &[Rule],
+    client_id: &str,
+    capabilities: &[PlainCapability],
+) -> Result<(), VapError> {
+    match capabilities
+        .iter()
+        .find(|c| !rules.iter().any(|r| r.allows(client_id, &c.name)))
+    {
+        Some(denied) => {
+            Err(VapError::new(UNAUTHORIZED, vap_error::UNAUTHORIZED).with_object(&denied.name))
+        }
+        None => Ok(()),
+    }
+}
+
+fn matches(allowed: &[String], name: &str) -> bool {
+    allowed.iter().any(|a| a == ANY || a == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vap_common_skill::structures::msg_query::QueryData;
+
+    const POLICY: &str = r#"
+        [default]
+
+        [skills."com.example.bank"]
+        query = [{ clients = ["kitchen"], capabilities = ["text"] }]
+        receive = ["voice"]
+    "#;
+
+    fn cap(name: &str) -> PlainCapability {
+        PlainCapability {
+            name: name.into(),
+            cap_data: Default::default(),
+        }
+    }
+
+    fn query(skill_id: &str, client_id: &str, capability: &str) -> MsgQuery {
+        MsgQuery {
+            skill_id: skill_id.into(),
+            data: vec![QueryData {
+                client_id: client_id.into(),
+                capabilities: vec![cap(capability)],
+            }],
+        }
+    }
+
+    #[test]
+    fn only_allows_what_is_written() {
+        let policy: Policy = POLICY.parse().unwrap();
+        assert!(policy.check_query(&query("com.example.bank", "kitchen", "text")).is_ok());
+
+        let err = policy
+            .check_query(&query("com.example.bank", "kitchen", "camera"))
+            .unwrap_err();
+        assert_eq!(err.code, 401);
+        assert_eq!(err.object.as_deref(), Some("camera"));
+
+        assert!(policy.check_query(&query("com.example.bank", "bedroom", "text")).is_err());
+        assert!(policy.check_query(&query("com.example.other", "kitchen", "text")).is_err());
+        assert!(policy.check_notify("com.example.bank", "kitchen", &[cap("text")]).is_err());
+
+        let received = policy.filter_received("com.example.bank", vec![cap("voice"), cap("camera")]);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].name, "voice");
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        let policy = Policy::default();
+        assert!(policy.check_query(&query("com.example.any", "kitchen", "camera")).is_ok());
+        assert!(policy.check_notify("com.example.any", "kitchen", &[cap("voice")]).is_ok());
+        assert_eq!(policy.filter_received("com.example.any", vec![cap("camera")]).len(), 1);
+    }
+}
@@ -0,0 +1,181 @@
+// OSCORE for the messages of skills (see SKILLS.MD)
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use coap_lite::{CoapRequest, CoapResponse, Packet, ResponseType};
+use vap_common_skill::oscore::{self, RequestRef, SecurityContext};
+use vap_common_skill::structures::vap_error;
+
+use crate::{respond_error, ConnectedSkill, SharedSkills};
+
+pub(crate) type SharedContext = Arc<SyncMutex<SecurityContext>>;
+
+/// What is needed to protect the answer to a protected request
+pub(crate) struct Protection {
+    context: SharedContext,
+    request: RequestRef,
+}
+
+/// Reads a protected request with the security context of its skill, requests
+/// that are not protected are left as they are. Requests that can't be read
+/// are answered right away, without protection.
+pub(crate) fn unprotect(
+    current_skills: &SharedSkills,
+    mut request: CoapRequest<SocketAddr>,
+) -> Result<(CoapRequest<SocketAddr>, Option<Protection>), Option<CoapResponse>> {
+    let bytes = match request.message.to_bytes() {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(respond_oscore_error(request.response, oscore::Error::Malformed)),
+    };
+
+    let id_context = match oscore::request_id_context(&bytes) {
+        Err(oscore::Error::NotProtected) => return Ok((request, None)),
+        Err(e) => return Err(respond_oscore_error(request.response, e)),
+        Ok(id_context) => id_context,
+    };
+
+    let context = current_skills.lock().unwrap()
+        .values()
+        .filter_map(|skill| skill.oscore.as_ref())
+        .find(|c| c.lock().unwrap().id_context() == id_context.as_deref())
+        .cloned();
+    let context = match context {
+        Some(context) => context,
+        None => return Err(respond_oscore_error(request.response, oscore::Error::UnknownContext)),
+    };
+
+    let unprotected = context.lock().unwrap().unprotect_request(&bytes);
+    match unprotected {
+        Ok((plain, request_ref)) => {
+            // The header and CoAP token are the same, the response stays valid
+            match Packet::from_bytes(&plain) {
+                Ok(packet) => {
+                    request.message = packet;
+                    Ok((request, Some(Protection {context, request: request_ref})))
+                }
+                Err(_) => Err(respond_oscore_error(request.response, oscore::Error::Malformed)),
+            }
+        }
+        Err(e) => Err(respond_oscore_error(request.response, e)),
+    }
+}
+
+/// Protects the answer to a request, if the request was protected
+pub(crate) fn protect(response: Option<CoapResponse>, protection: Option<Protection>) -> Option<CoapResponse> {
+    let (mut response, protection) = match (response, protection) {
+        (Some(response), Some(protection)) => (response, protection),
+        (response, _) => return response,
+    };
+
+    let protected = response.message.to_bytes().ok()
+        .and_then(|bytes| protection.context.lock().unwrap().protect_response(&bytes, &protection.request).ok())
+        .and_then(|bytes| Packet::from_bytes(&bytes).ok());
+    match protected {
+        Some(packet) => {
+            response.message = packet;
+            Some(response)
+        }
+        None => {
+            println!("Could not protect an answer");
+            respond_error(Some(response), ResponseType::InternalServerError, vap_error::INTERNAL_ERROR, None)
+        }
+    }
+}
+
+/// The token a skill observes its requests with when it uses OSCORE, `None` if
+/// the connection has no security context
+pub(crate) fn observation_token(skill: &ConnectedSkill) -> Option<String> {
+    let context = skill.oscore.as_ref()?.lock().unwrap();
+    let id_context = std::str::from_utf8(context.id_context()?).ok()?;
+    Some(oscore::observation_token(&skill.token, id_context))
+}
+
+/// Errors of OSCORE are answered as in RFC 8613 (section 8.2), without protection
+fn respond_oscore_error(resp: Option<CoapResponse>, e: oscore::Error) -> Option<CoapResponse> {
+    println!("Could not read a protected request: {}", e);
+    match e {
+        oscore::Error::Malformed => {
+            respond_error(resp, ResponseType::BadOption, vap_error::MALFORMED_CONTENT, None)
+        }
+        oscore::Error::UnknownContext | oscore::Error::Replayed => {
+            respond_error(resp, ResponseType::Unauthorized, vap_error::UNAUTHORIZED, None)
+        }
+        _ => respond_error(resp, ResponseType::BadRequest, vap_error::MALFORMED_CONTENT, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{connected_skill, TOKEN};
+
+    use coap_lite::{CoapOption, MessageClass, RequestType as Method};
+    use std::collections::HashMap;
+    use vap_common_skill::structures::AUTH_TOKEN_OPTION;
+
+    const ID: &str = "com.example.test";
+    const ID_CONTEXT: &str = "context";
+
+    fn connected() -> SharedSkills {
+        let mut skill = connected_skill("Test");
+        skill.oscore = Some(Arc::new(SyncMutex::new(oscore::registry_context(TOKEN, ID_CONTEXT))));
+        let mut skills = HashMap::new();
+        skills.insert(ID.to_string(), skill);
+        Arc::new(SyncMutex::new(skills))
+    }
+
+    fn request(packet: &[u8]) -> CoapRequest<SocketAddr> {
+        CoapRequest::from_packet(Packet::from_bytes(packet).unwrap(), "127.0.0.1:5000".parse().unwrap())
+    }
+
+    #[test]
+    fn protected_requests_are_read_and_answered() {
+        let skills = connected();
+        let mut skill = oscore::skill_context(TOKEN, ID_CONTEXT);
+
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(Method::Get);
+        packet.set_token(vec![0xAA]);
+        packet.add_option(CoapOption::UriPath, b"vap".to_vec());
+        packet.add_option(CoapOption::UriPath, b"skillRegistry".to_vec());
+        packet.add_option(CoapOption::UriPath, b"query".to_vec());
+        packet.add_option(CoapOption::Unknown(AUTH_TOKEN_OPTION), TOKEN.into());
+        let (protected, request_ref) = skill.protect_request(&packet.to_bytes().unwrap()).unwrap();
+
+        let (unprotected, protection) = unprotect(&skills, request(&protected)).unwrap();
+        assert_eq!(unprotected.get_method(), &Method::Get);
+        assert_eq!(unprotected.get_path(), "vap/skillRegistry/query");
+        assert!(protection.is_some());
+
+        let mut response = unprotected.response;
+        response.as_mut().unwrap().set_status(ResponseType::Content);
+        let response = protect(response, protection).unwrap();
+        let response = skill.unprotect_response(&response.message.to_bytes().unwrap(), &request_ref).unwrap();
+        assert_eq!(Packet::from_bytes(&response).unwrap().header.code, MessageClass::Response(ResponseType::Content));
+
+        // The same request can't be used twice
+        let resp = unprotect(&skills, request(&protected)).err().unwrap().unwrap();
+        assert_eq!(resp.get_status(), &ResponseType::Unauthorized);
+    }
+
+    #[test]
+    fn other_contexts_are_unauthorized() {
+        let mut skill = oscore::skill_context(TOKEN, "other");
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(Method::Get);
+        let (protected, _) = skill.protect_request(&packet.to_bytes().unwrap()).unwrap();
+
+        let resp = unprotect(&connected(), request(&protected)).err().unwrap().unwrap();
+        assert_eq!(resp.get_status(), &ResponseType::Unauthorized);
+    }
+
+    #[test]
+    fn plain_requests_are_left_alone() {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(Method::Get);
+        let (request, protection) = unprotect(&connected(), request(&packet.to_bytes().unwrap())).unwrap();
+        assert_eq!(request.get_method(), &Method::Get);
+        assert!(protection.is_none());
+    }
+}
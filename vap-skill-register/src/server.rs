@@ -0,0 +1,458 @@
+//! The CoAP server of the registry. Skills receive their requests by observing
+//! `vap/skillRegistry/skills/{id}`, those observations are kept here so that
+//! requests are notified to the skill right away. They are forgotten once the
+//! skill closes its connection (`DELETE` of that same path) or is removed.
+//! Notifications are confirmable, a skill that resets one or never acknowledges
+//! it is not getting its requests.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex as SyncMutex;
+use std::time::Duration;
+
+use coap_lite::{
+    CoapRequest, CoapResponse, MessageClass, MessageType, ObserveOption, Packet,
+    RequestType as Method, ResponseType,
+};
+use futures::channel::oneshot;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::UdpSocket;
+
+const SKILLS_PATH: &str = "vap/skillRegistry/skills/";
+/// "All CoAP Nodes", skills that don't know the address of the registry look here
+const ALL_COAP_NODES: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+/// Big enough for any UDP datagram
+const MAX_DATAGRAM: usize = 65535;
+/// Requests handled at the same time, no more messages are read until one of
+/// them is answered
+const MAX_HANDLING: usize = 64;
+/// How long to wait for the first acknowledgement of a notification, doubled
+/// with each retransmission (RFC 7252, section 4.8)
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+#[derive(Debug)]
+struct Observer {
+    address: SocketAddr,
+    /// Notifications carry the token of the request that started the observation
+    token: Vec<u8>,
+}
+
+/// A notification waiting for its acknowledgement
+#[derive(Debug)]
+struct Unconfirmed {
+    skill_id: String,
+    /// Told whether the skill acknowledged it (true) or reset it (false)
+    confirmed: oneshot::Sender<bool>,
+}
+
+#[derive(Debug)]
+struct Observers {
+    by_skill: HashMap<String, Observer>,
+    /// By message id
+    unconfirmed: HashMap<u16, Unconfirmed>,
+    next_message_id: u16,
+    next_observe: u32,
+}
+
+impl Observers {
+    fn next_message_id(&mut self) -> u16 {
+        let id = self.next_message_id;
+        self.next_message_id = id.wrapping_add(1);
+        id
+    }
+
+    /// Observe values are 24 bits long, and must grow with each notification
+    fn next_observe(&mut self) -> u32 {
+        let observe = self.next_observe;
+        self.next_observe = (observe + 1) & 0xFF_FFFF;
+        observe
+    }
+}
+
+pub(crate) struct CoapServer {
+    socket: UdpSocket,
+    observers: SyncMutex<Observers>,
+}
+
+impl CoapServer {
+    /// Serves on a socket bound beforehand (e.g: to know its port right away)
+    pub(crate) fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        if let Err(e) = socket.join_multicast_v4(ALL_COAP_NODES, Ipv4Addr::UNSPECIFIED) {
+            println!("Couldn't join All CoAP Nodes, skills won't discover the registry: {}", e);
+        }
+
+        Ok(CoapServer {
+            socket,
+            observers: SyncMutex::new(Observers {
+                by_skill: HashMap::new(),
+                unconfirmed: HashMap::new(),
+                next_message_id: rand::random(),
+                next_observe: 0,
+            }),
+        })
+    }
+
+    /// Answers requests with `handler`, several at a time (e.g: a connect
+    /// waiting for the host doesn't hold up heartbeats) but no more than
+    /// `MAX_HANDLING`. Observations of `vap/skillRegistry/skills/{id}` are kept
+    /// if the handler accepts them.
+    pub(crate) async fn serve<F, R>(&self, mut handler: F)
+    where
+        F: FnMut(CoapRequest<SocketAddr>) -> R,
+        R: Future<Output = Option<CoapResponse>>,
+    {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut handling = FuturesUnordered::new();
+        loop {
+            if handling.len() >= MAX_HANDLING {
+                if let Some((response, source, token, observation)) = handling.next().await {
+                    self.respond(response, source, token, observation).await;
+                }
+                continue;
+            }
+
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, source) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            println!("Couldn't receive a message: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(request) = self.read_request(&buf[..len], source) {
+                        let observation = observation(&request);
+                        let token = request.message.get_token().clone();
+                        let response = handler(request);
+                        handling.push(async move { (response.await, source, token, observation) });
+                    }
+                },
+                Some((response, source, token, observation)) = handling.next() => {
+                    self.respond(response, source, token, observation).await;
+                },
+            }
+        }
+    }
+
+    /// The request in a message, other messages are handled here
+    fn read_request(&self, bytes: &[u8], source: SocketAddr) -> Option<CoapRequest<SocketAddr>> {
+        let packet = match Packet::from_bytes(bytes) {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("Received a bad CoAP message from {}: {}", source, e);
+                return None;
+            }
+        };
+
+        if !matches!(packet.header.code, MessageClass::Request(_)) {
+            let message_type = packet.header.get_type();
+            let mut observers = self.observers.lock().unwrap();
+            let unconfirmed = match message_type {
+                MessageType::Acknowledgement | MessageType::Reset => {
+                    observers.unconfirmed.remove(&packet.header.message_id)
+                }
+                _ => None,
+            };
+
+            // A reset means the skill doesn't want notifications anymore
+            let reset = message_type == MessageType::Reset;
+            if reset {
+                match &unconfirmed {
+                    Some(unconfirmed) => {
+                        observers.by_skill.remove(&unconfirmed.skill_id);
+                    }
+                    None => observers.by_skill.retain(|_, o| o.address != source),
+                }
+            }
+            if let Some(unconfirmed) = unconfirmed {
+                let _ = unconfirmed.confirmed.send(!reset);
+            }
+            return None;
+        }
+
+        Some(CoapRequest::from_packet(packet, source))
+    }
+
+    /// Sends the answer of the handler, and keeps or forgets the observation
+    /// if the request was about it and the handler accepted it
+    async fn respond(
+        &self,
+        mut response: Option<CoapResponse>,
+        source: SocketAddr,
+        token: Vec<u8>,
+        observation: Option<(String, ObserveOption)>,
+    ) {
+        if let (Some((skill_id, flag)), Some(resp)) = (observation, &mut response) {
+            let code = resp.message.header.code;
+            let mut observers = self.observers.lock().unwrap();
+            match flag {
+                ObserveOption::Register if code == MessageClass::Response(ResponseType::Content) => {
+                    resp.message.set_observe_value(observers.next_observe());
+                    observers
+                        .by_skill
+                        .insert(skill_id, Observer { address: source, token });
+                }
+                ObserveOption::Deregister
+                    if code == MessageClass::Response(ResponseType::Content)
+                        || code == MessageClass::Response(ResponseType::Deleted) =>
+                {
+                    observers.by_skill.remove(&skill_id);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(resp) = response {
+            self.send(&resp.message, source).await;
+        }
+    }
+
+    /// Stops notifying the requests of a skill (e.g: it was removed)
+    pub(crate) fn forget(&self, skill_id: &str) {
+        self.observers.lock().unwrap().by_skill.remove(skill_id);
+    }
+
+    /// Sends a payload to the skill observing its requests and waits for it to
+    /// be acknowledged, returns false if the skill is not observing them, if
+    /// it resets the notification or if it never acknowledges it
+    pub(crate) async fn notify(&self, skill_id: &str, payload: Vec<u8>) -> bool {
+        let (packet, address, mut confirmed) = {
+            let mut observers = self.observers.lock().unwrap();
+            let message_id = observers.next_message_id();
+            let observe = observers.next_observe();
+            let observer = match observers.by_skill.get(skill_id) {
+                Some(observer) => observer,
+                None => return false,
+            };
+
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::Confirmable);
+            packet.header.code = MessageClass::Response(ResponseType::Content);
+            packet.header.message_id = message_id;
+            packet.set_token(observer.token.clone());
+            packet.set_observe_value(observe);
+            packet.payload = payload;
+            let address = observer.address;
+
+            let (sender, receiver) = oneshot::channel();
+            observers.unconfirmed.insert(
+                message_id,
+                Unconfirmed { skill_id: skill_id.to_string(), confirmed: sender },
+            );
+            (packet, address, receiver)
+        };
+
+        let mut timeout = ACK_TIMEOUT;
+        for _ in 0..=MAX_RETRANSMIT {
+            self.send(&packet, address).await;
+            if let Ok(answer) = tokio::time::timeout(timeout, &mut confirmed).await {
+                return answer.unwrap_or(false);
+            }
+            timeout *= 2;
+        }
+
+        self.observers.lock().unwrap().unconfirmed.remove(&packet.header.message_id);
+        false
+    }
+
+    async fn send(&self, packet: &Packet, address: SocketAddr) {
+        let sent = match packet.to_bytes() {
+            Ok(bytes) => self.socket.send_to(&bytes, address).await.map(|_| ()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        };
+        if let Err(e) = sent {
+            println!("Couldn't send a message to {}: {}", address, e);
+        }
+    }
+}
+
+/// The skill whose requests are (un)observed, if the request does so. Closing
+/// the connection (`DELETE`) ends the observation too.
+fn observation(request: &CoapRequest<SocketAddr>) -> Option<(String, ObserveOption)> {
+    let path = request.get_path();
+    let skill_id = path.strip_prefix(SKILLS_PATH)?.to_string();
+    match (request.get_method(), request.get_observe_flag()) {
+        (Method::Get, Some(Ok(flag))) => Some((skill_id, flag)),
+        (Method::Delete, _) => Some((skill_id, ObserveOption::Deregister)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    const SKILL_PATH: &str = "vap/skillRegistry/skills/com.example.test";
+
+    fn request(method: Method, path: &str) -> CoapRequest<SocketAddr> {
+        let mut request = CoapRequest::new();
+        request.message.header.set_type(MessageType::Confirmable);
+        request.set_method(method);
+        request.set_path(path);
+        request
+    }
+
+    /// Answers with the message sent by the server
+    fn bind() -> (CoapServer, u16) {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        (CoapServer::from_std(socket).unwrap(), port)
+    }
+
+    async fn exchange(socket: &UdpSocket, port: u16, request: &CoapRequest<SocketAddr>) -> Packet {
+        let bytes = request.message.to_bytes().unwrap();
+        socket.send_to(&bytes, ("127.0.0.1", port)).await.unwrap();
+        receive(socket).await
+    }
+
+    async fn receive(socket: &UdpSocket) -> Packet {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        Packet::from_bytes(&buf[..len]).unwrap()
+    }
+
+    /// Receives a notification and answers it with a message of this type
+    async fn answer_notification(socket: &UdpSocket, port: u16, message_type: MessageType) -> Packet {
+        let notification = receive(socket).await;
+        assert_eq!(notification.header.get_type(), MessageType::Confirmable);
+        let mut answer = Packet::new();
+        answer.header.set_type(message_type);
+        answer.header.code = MessageClass::Empty;
+        answer.header.message_id = notification.header.message_id;
+        socket.send_to(&answer.to_bytes().unwrap(), ("127.0.0.1", port)).await.unwrap();
+        notification
+    }
+
+    fn observe() -> CoapRequest<SocketAddr> {
+        let mut observe = request(Method::Get, SKILL_PATH);
+        observe.message.set_token(vec![7]);
+        observe.set_observe_flag(ObserveOption::Register);
+        observe
+    }
+
+    fn is(packet: &Packet, code: ResponseType) -> bool {
+        packet.header.code == MessageClass::Response(code)
+    }
+
+    #[tokio::test]
+    async fn slow_requests_dont_hold_up_the_rest() {
+        let (server, port) = bind();
+        let fast_done = AtomicBool::new(false);
+        let handler = |request: CoapRequest<SocketAddr>| {
+            let fast_done = &fast_done;
+            async move {
+                let path = request.get_path();
+                // The slow one can only finish after the fast one
+                if path == "slow" {
+                    while !fast_done.load(Ordering::SeqCst) {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                } else {
+                    fast_done.store(true, Ordering::SeqCst);
+                }
+                let mut response = request.response?;
+                response.set_status(ResponseType::Content);
+                response.message.payload = path.into_bytes();
+                Some(response)
+            }
+        };
+
+        let client = async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for path in ["slow", "fast"] {
+                let bytes = request(Method::Get, path).message.to_bytes().unwrap();
+                socket.send_to(&bytes, ("127.0.0.1", port)).await.unwrap();
+            }
+            assert_eq!(receive(&socket).await.payload, b"fast");
+            assert_eq!(receive(&socket).await.payload, b"slow");
+        };
+
+        let served = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                _ = server.serve(handler) => {},
+                _ = client => {},
+            }
+        });
+        served.await.expect("The slow request held up the fast one");
+    }
+
+    #[tokio::test]
+    async fn observations_end_with_the_connection() {
+        let (server, port) = bind();
+        let handler = |request: CoapRequest<SocketAddr>| async move {
+            let status = match *request.get_method() {
+                Method::Delete => ResponseType::Deleted,
+                _ => ResponseType::Content,
+            };
+            let mut response = request.response?;
+            response.set_status(status);
+            Some(response)
+        };
+
+        let client = async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            assert!(is(&exchange(&socket, port, &observe()).await, ResponseType::Content));
+            let (delivered, notification) = futures::future::join(
+                server.notify("com.example.test", b"request".to_vec()),
+                answer_notification(&socket, port, MessageType::Acknowledgement),
+            ).await;
+            assert!(delivered);
+            assert_eq!(notification.get_token(), &[7]);
+            assert_eq!(notification.payload, b"request");
+
+            let close = exchange(&socket, port, &request(Method::Delete, SKILL_PATH)).await;
+            assert!(is(&close, ResponseType::Deleted));
+            assert!(!server.notify("com.example.test", vec![]).await);
+
+            // Removed skills are forgotten too
+            assert!(is(&exchange(&socket, port, &observe()).await, ResponseType::Content));
+            server.forget("com.example.test");
+            assert!(!server.notify("com.example.test", vec![]).await);
+        };
+
+        let served = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                _ = server.serve(handler) => {},
+                _ = client => {},
+            }
+        });
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reset_notifications_end_the_observation() {
+        let (server, port) = bind();
+        let handler = |request: CoapRequest<SocketAddr>| async move {
+            let mut response = request.response?;
+            response.set_status(ResponseType::Content);
+            Some(response)
+        };
+
+        let client = async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            assert!(is(&exchange(&socket, port, &observe()).await, ResponseType::Content));
+            let (delivered, _) = futures::future::join(
+                server.notify("com.example.test", b"request".to_vec()),
+                answer_notification(&socket, port, MessageType::Reset),
+            ).await;
+            assert!(!delivered);
+            assert!(!server.notify("com.example.test", vec![]).await);
+        };
+
+        // Without waiting for the retransmissions
+        let served = tokio::time::timeout(Duration::from_secs(1), async {
+            tokio::select! {
+                _ = server.serve(handler) => {},
+                _ = client => {},
+            }
+        });
+        served.await.unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Where the registry keeps the connected skills (their tokens and intents
+//! included), so that they survive a restart of the registry.
+
+use std::fmt::Debug;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use crate::Error;
+
+pub(crate) type SharedStorage = Arc<SyncMutex<Saved>>;
+
+/// A storage and the number of the last save written to it, saves are written
+/// apart from the registry and can finish out of order
+#[derive(Debug)]
+pub(crate) struct Saved {
+    pub(crate) storage: Box<dyn Storage>,
+    pub(crate) last: u64,
+}
+
+/// A place to keep the connected skills. What is kept is opaque to the
+/// storage, implement this to keep it somewhere else than a file (e.g: a database).
+pub trait Storage: Debug + Send {
+    /// What was saved last, `None` if nothing was saved yet
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Replaces whatever was saved before
+    fn save(&mut self, data: &[u8]) -> Result<(), Error>;
+}
+
+/// Keeps the connected skills in a file, it is created if it does not exist
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileStorage { path: path.into() }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(&self.path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&mut self, data: &[u8]) -> Result<(), Error> {
+        // Write somewhere else first, a crash while writing won't lose everything
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_storage_keeps_the_last_save() {
+        let path = std::env::temp_dir().join(format!("vap-storage-{}.msgpack", std::process::id()));
+        let mut storage = FileStorage::new(&path);
+        assert_eq!(storage.load().unwrap(), None);
+
+        storage.save(b"first").unwrap();
+        storage.save(b"second").unwrap();
+        assert_eq!(storage.load().unwrap().as_deref(), Some(&b"second"[..]));
+
+        fs::remove_file(path).unwrap();
+    }
+}
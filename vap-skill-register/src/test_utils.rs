@@ -0,0 +1,35 @@
+// Fixtures shared by the tests of every module
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use coap_lite::{CoapRequest, MessageClass, Packet, RequestType as Method};
+use serde::Serialize;
+
+use crate::{encoding, ConnectedSkill, VAP_VERSION};
+
+pub(crate) const TOKEN: &str = "token";
+
+/// A skill connected with `TOKEN`, without intents nor OSCORE
+pub(crate) fn connected_skill(name: &str) -> ConnectedSkill {
+    ConnectedSkill {
+        name: name.into(),
+        version: VAP_VERSION,
+        token: TOKEN.into(),
+        oscore: None,
+        intents: None,
+        intents_digest: None,
+        last_seen: Instant::now(),
+        restored: false,
+    }
+}
+
+/// A request as the server hands it to the handlers
+pub(crate) fn request<T: Serialize>(method: Method, path: &str, payload: &T) -> CoapRequest<SocketAddr> {
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::Request(method);
+    packet.payload = encoding::to_vec(payload).unwrap();
+    let mut request = CoapRequest::from_packet(packet, "127.0.0.1:5000".parse().unwrap());
+    request.set_path(path);
+    request
+}
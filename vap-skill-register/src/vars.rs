@@ -1,6 +1,16 @@
 // Some vars to be exported (though some are used internally as well)
 
-/// VAP version implemented by this crate
-pub const VAP_VERSION: &str = "Alpha";
+use vap_common_skill::version::{VersionRange, MIN_VAP_VERSION};
+
+/// Newest VAP version implemented by this crate
+pub use vap_common_skill::version::VAP_VERSION;
+/// Versions of VAP that skills can use to connect to this register
+pub const SUPPORTED_VAP_VERSIONS: VersionRange = VersionRange::new(MIN_VAP_VERSION, VAP_VERSION);
+/// Seconds a skill waiting for the user's approval is told to wait before trying again
+pub const APPROVAL_RETRY_SECS: u32 = 5;
+/// Heartbeats a skill can miss before being regarded as gone
+pub const MISSED_HEARTBEATS: u32 = 3;
+/// Seconds restored skills have to connect again, unless configured otherwise
+pub const RESTORE_GRACE_SECS: u32 = 300;
 /// The name used to refer to the skill register itself
-pub const SYSTEM_SELF_ID: &str = "vap.SYSTEM";
+pub use vap_common_skill::structures::SYSTEM_SELF_ID;